@@ -31,13 +31,21 @@ local function load_website_config()
                 local after_websites = content:sub(websites_start)
 
                 -- Parse boolean values with error handling
-                for key, value in after_websites:gmatch("(%w+)%s*=%s*(%w+)") do
+                for key, value in after_websites:gmatch("([%w_]+)%s*=%s*(%w+)") do
                     if key == "youtube" or key == "youtube_alternatives" or
                         key == "vimeo" or key == "dailymotion" or key == "twitch" then
                         config[key] = (value:lower() == "true")
                     end
                 end
 
+                -- A [websites.<site>] profile table enables the site unless it
+                -- sets enabled = false
+                for key, body in after_websites:gmatch("%[websites%.([%w_]+)%]([^%[]*)") do
+                    if config[key] ~= nil and key ~= "custom_domains" then
+                        config[key] = not body:match("enabled%s*=%s*false")
+                    end
+                end
+
                 -- Parse custom_domains with error handling
                 local domains_line = after_websites:match("custom_domains%s*=%s*%[([^%]]+)%]")
                 if domains_line then
@@ -62,6 +70,50 @@ local function load_website_config()
     return config
 end
 
+-- Lowercase host (without port or trailing dot) and path of an http(s) URL
+local function url_host_path(url)
+    local authority, path = url:match("^[Hh][Tt][Tt][Pp][Ss]?://([^/?#]+)([^?#]*)")
+    if not authority then
+        return nil
+    end
+    local host = authority:gsub("^.*@", ""):gsub(":%d+$", ""):gsub("%.$", ""):lower()
+    if path == "" then
+        path = "/"
+    end
+    return host, path
+end
+
+-- Same rules as the proxy: "example.com" matches that host only, "*.example.com"
+-- its subdomains, and a trailing path ("example.com/videos/") a path prefix.
+-- "re:" patterns can't be evaluated in Lua, so they count as a match here and
+-- the proxy decides: hosts its regex rejects are tunnelled or passed through.
+local function matches_site_pattern(pattern, host, path)
+    if pattern:sub(1, 3) == "re:" then
+        return true
+    end
+
+    local rest = pattern:gsub("^[Hh][Tt][Tt][Pp][Ss]?://", "")
+    local pattern_host, prefix = rest:match("^([^/]*)(.*)$")
+    pattern_host = pattern_host:gsub(":%d+$", ""):gsub("%.$", ""):lower()
+
+    local host_matches
+    if pattern_host:sub(1, 2) == "*." then
+        local suffix = pattern_host:sub(2)
+        host_matches = #host > #suffix and host:sub(-#suffix) == suffix
+    else
+        host_matches = host == pattern_host
+    end
+    if not host_matches or prefix == "" then
+        return host_matches
+    end
+    -- Whole path segments only: "/videos" covers "/videos" and "/videos/1", not "/videos2"
+    if path:sub(1, #prefix) ~= prefix then
+        return false
+    end
+    local rest = path:sub(#prefix + 1)
+    return prefix:sub(-1) == "/" or rest == "" or rest:sub(1, 1) == "/"
+end
+
 -- Enhanced URL validation with configurable website support
 local function is_supported_url(url, config)
     if not url or type(url) ~= "string" then
@@ -72,7 +124,8 @@ local function is_supported_url(url, config)
     if config.youtube then
         local youtube_patterns = {
             "^https://[%w%-%.]*youtube%.com/",
-            "^https://[%w%-%.]*youtu%.be/"
+            "^https://[%w%-%.]*youtu%.be/",
+            "^https://[%w%-%.]*%.googlevideo%.com/"
         }
         for _, pattern in ipairs(youtube_patterns) do
             if url:match(pattern) then
@@ -96,25 +149,27 @@ local function is_supported_url(url, config)
     end
 
     -- Vimeo
-    if config.vimeo and url:match("^https://[%w%-%.]*vimeo%.com/") then
+    if config.vimeo and (url:match("^https://[%w%-%.]*vimeo%.com/") or url:match("^https://[%w%-%.]*%.vimeocdn%.com/")) then
         return true
     end
 
     -- Dailymotion
-    if config.dailymotion and url:match("^https://[%w%-%.]*dailymotion%.com/") then
+    if config.dailymotion and (url:match("^https://[%w%-%.]*dailymotion%.com/") or url:match("^https://[%w%-%.]*%.dmcdn%.net/")) then
         return true
     end
 
     -- Twitch
-    if config.twitch and url:match("^https://[%w%-%.]*twitch%.tv/") then
+    if config.twitch and (url:match("^https://[%w%-%.]*twitch%.tv/") or url:match("^https://[%w%-%.]*%.ttvnw%.net/")) then
         return true
     end
 
     -- Custom domains
-    for _, domain in ipairs(config.custom_domains) do
-        -- Simple string.find is more reliable than complex pattern matching
-        if url:find(domain, 1, true) then -- plain text search
-            return true
+    local host, path = url_host_path(url)
+    if host then
+        for _, pattern in ipairs(config.custom_domains) do
+            if matches_site_pattern(pattern, host, path) then
+                return true
+            end
         end
     end
 
@@ -171,20 +226,21 @@ local function init()
     local proxy = mp.get_property("http-proxy")
     local ytdl_raw_options = mp.get_property("ytdl-raw-options")
 
-    -- Check if another proxy is already configured
-    local our_proxy = "http://127.0.0.1:" .. proxy_port
-    if (proxy and proxy ~= "" and proxy ~= our_proxy) or
-        (ytdl_raw_options and ytdl_raw_options:match("proxy=([^ ]+)")) then
-        mp.msg.warn("Another proxy is already configured, skipping YouTube proxy")
-        return
-    end
-
     -- Skip if our proxy is already running
+    local our_proxy = "http://127.0.0.1:" .. proxy_port
     if proxy == our_proxy then
         mp.msg.info("YouTube proxy already active")
         return
     end
 
+    -- Chain through an already configured proxy instead of bypassing it
+    local upstream_proxy = nil
+    if proxy and proxy ~= "" then
+        upstream_proxy = proxy
+    elseif ytdl_raw_options then
+        upstream_proxy = ytdl_raw_options:match("proxy=([^,]+)")
+    end
+
     -- Check if binary exists
     local script_dir = mp.get_script_directory()
     local binary_path = script_dir .. "/http-ytproxy"
@@ -222,6 +278,12 @@ local function init()
         "-p", proxy_port
     }
 
+    if upstream_proxy then
+        mp.msg.info("Chaining through upstream proxy: " .. upstream_proxy)
+        table.insert(args, "--upstream")
+        table.insert(args, upstream_proxy)
+    end
+
     proxy_process = mp.command_native_async({
         name = "subprocess",
         capture_stdout = false,
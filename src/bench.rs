@@ -0,0 +1,329 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode, Uri};
+
+use crate::proxy::UpstreamClient;
+use crate::resource::{parse_content_range, parse_range};
+use crate::Error;
+
+// The local test origin behaves like a video CDN: every response waits before
+// its first byte and is paced to a per-response rate, and requests beyond a
+// number of concurrent ones are refused with 429
+const ORIGIN_LENGTH: u64 = 256 * 1024 * 1024;
+const ORIGIN_FIRST_BYTE: Duration = Duration::from_millis(50);
+const ORIGIN_RATE: u64 = 16 * 1024 * 1024;
+const ORIGIN_MAX_ACTIVE: usize = 6;
+const ORIGIN_SLICE: usize = 64 * 1024;
+
+// Redirects followed when probing the target
+const MAX_REDIRECTS: usize = 5;
+
+// Settings within this fraction of the best throughput count as equally fast
+const THROUGHPUT_TOLERANCE: f64 = 0.05;
+
+// Plain HTTP origin on 127.0.0.1 for benchmarking without a network
+pub struct LocalOrigin {
+    addr: SocketAddr,
+}
+
+impl LocalOrigin {
+    pub async fn start() -> Result<Self, Error> {
+        let active = Arc::new(AtomicUsize::new(0));
+        let block = Bytes::from((0..ORIGIN_SLICE).map(|i| (i % 251) as u8).collect::<Vec<u8>>());
+        let make_service = make_service_fn(move |_| {
+            let active = active.clone();
+            let block = block.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let response = serve_origin(&req, &active, &block);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Ok(Self { addr })
+    }
+
+    pub fn url(&self) -> Uri {
+        format!("http://{}/bench.bin", self.addr).parse().unwrap()
+    }
+
+    pub fn describe() -> String {
+        format!(
+            "{}ms to first byte, {}/s per response, 429 above {} concurrent requests",
+            ORIGIN_FIRST_BYTE.as_millis(),
+            format_size(ORIGIN_RATE),
+            ORIGIN_MAX_ACTIVE
+        )
+    }
+}
+
+fn serve_origin(req: &Request<Body>, active: &Arc<AtomicUsize>, block: &Bytes) -> Response<Body> {
+    let range = req
+        .headers()
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_range);
+    let Some((start, end)) = range.filter(|(start, _)| *start < ORIGIN_LENGTH) else {
+        return origin_status(StatusCode::RANGE_NOT_SATISFIABLE);
+    };
+    let end = end.unwrap_or(ORIGIN_LENGTH - 1).min(ORIGIN_LENGTH - 1);
+
+    if active.fetch_add(1, Ordering::AcqRel) >= ORIGIN_MAX_ACTIVE {
+        active.fetch_sub(1, Ordering::AcqRel);
+        return origin_status(StatusCode::TOO_MANY_REQUESTS);
+    }
+    let slot = ActiveRequest(active.clone());
+
+    let block = block.clone();
+    let remaining = end - start + 1;
+    let body = stream::unfold((remaining, true, slot), move |(remaining, first, slot)| {
+        let block = block.clone();
+        async move {
+            if remaining == 0 {
+                return None;
+            }
+            if first {
+                tokio::time::sleep(ORIGIN_FIRST_BYTE).await;
+            }
+            let size = remaining.min(ORIGIN_SLICE as u64);
+            tokio::time::sleep(Duration::from_secs_f64(size as f64 / ORIGIN_RATE as f64)).await;
+            let data = block.slice(..size as usize);
+            Some((Ok::<_, Infallible>(data), (remaining - size, false, slot)))
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(CONTENT_TYPE, "video/mp4")
+        .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, ORIGIN_LENGTH))
+        .header(CONTENT_LENGTH, remaining)
+        .body(Body::wrap_stream(body))
+        .unwrap()
+}
+
+fn origin_status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+// Counts a response as active until its body is done or dropped
+struct ActiveRequest(Arc<AtomicUsize>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// What one setting of the matrix achieved
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub chunk_size: u64,
+    pub concurrency: usize,
+    // Body bytes received
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub requests: usize,
+    // Requests answered with 429 Too Many Requests
+    pub throttled: usize,
+    // Requests that errored or got any other unexpected answer
+    pub failed: usize,
+    // Time to first body byte of each successful request, sorted
+    ttfb: Vec<Duration>,
+}
+
+impl BenchResult {
+    // Bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    // Nearest-rank percentile of the time to first byte
+    pub fn ttfb(&self, percentile: f64) -> Option<Duration> {
+        let rank = ((percentile / 100.0) * self.ttfb.len() as f64).ceil() as usize;
+        self.ttfb.get(rank.clamp(1, self.ttfb.len().max(1)) - 1).copied()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.throttled == 0 && self.failed == 0 && self.bytes > 0
+    }
+}
+
+// How one chunk request went
+enum Outcome {
+    Fetched { bytes: u64, ttfb: Option<Duration> },
+    Throttled,
+    Failed { bytes: u64 },
+}
+
+// Fetches a target in chunks, the way the proxy would, with a given chunk
+// size and number of concurrent requests
+pub struct Bench {
+    client: UpstreamClient,
+    url: Uri,
+    length: u64,
+}
+
+impl Bench {
+    // Check that the target serves byte ranges and learn its length,
+    // following redirects
+    pub async fn probe(client: UpstreamClient, url: Uri) -> Result<Self, Error> {
+        let mut url = url;
+        for _ in 0..=MAX_REDIRECTS {
+            let response = client.request(range_request(&url, 0, 0)?).await?;
+            let status = response.status();
+            if status.is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .ok_or_else(|| format!("{} redirect without a Location", status))?;
+                url = location
+                    .parse()
+                    .map_err(|e| format!("Cannot follow redirect to '{}': {}", location, e))?;
+                continue;
+            }
+
+            return match status {
+                StatusCode::PARTIAL_CONTENT => {
+                    let length = response
+                        .headers()
+                        .get(CONTENT_RANGE)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(parse_content_range)
+                        .and_then(|(_, _, length)| length)
+                        .ok_or("the response has no Content-Range length")?;
+                    Ok(Self { client, url, length })
+                }
+                StatusCode::OK => Err("the target ignores Range requests, so chunk size makes no difference".into()),
+                StatusCode::TOO_MANY_REQUESTS => Err("the target answered 429 to the first request; try again later".into()),
+                status => Err(format!("the target answered {}", status).into()),
+            };
+        }
+        Err(format!("more than {} redirects", MAX_REDIRECTS).into())
+    }
+
+    // Where the bytes come from, after redirects
+    pub fn url(&self) -> &Uri {
+        &self.url
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    // Fetch the first `bytes` of the target in `chunk_size` requests, at most
+    // `concurrency` at a time
+    pub async fn run(&self, chunk_size: u64, concurrency: usize, bytes: u64) -> BenchResult {
+        let total = bytes.min(self.length);
+        let chunk_size = chunk_size.max(1);
+        let ranges: Vec<(u64, u64)> = (0..total)
+            .step_by(chunk_size as usize)
+            .map(|start| (start, (start + chunk_size).min(total) - 1))
+            .collect();
+
+        let started = Instant::now();
+        let outcomes: Vec<Outcome> = stream::iter(ranges)
+            .map(|(start, end)| self.fetch(start, end))
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+
+        let mut result = BenchResult {
+            chunk_size,
+            concurrency,
+            bytes: 0,
+            elapsed: started.elapsed(),
+            requests: outcomes.len(),
+            throttled: 0,
+            failed: 0,
+            ttfb: Vec::new(),
+        };
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Fetched { bytes, ttfb } => {
+                    result.bytes += bytes;
+                    result.ttfb.extend(ttfb);
+                }
+                Outcome::Throttled => result.throttled += 1,
+                Outcome::Failed { bytes } => {
+                    result.bytes += bytes;
+                    result.failed += 1;
+                }
+            }
+        }
+        result.ttfb.sort();
+        result
+    }
+
+    async fn fetch(&self, start: u64, end: u64) -> Outcome {
+        let Ok(req) = range_request(&self.url, start, end) else {
+            return Outcome::Failed { bytes: 0 };
+        };
+        let sent = Instant::now();
+        let mut body = match self.client.request(req).await {
+            Ok(response) if response.status() == StatusCode::PARTIAL_CONTENT => response.into_body(),
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => return Outcome::Throttled,
+            _ => return Outcome::Failed { bytes: 0 },
+        };
+
+        let mut bytes = 0;
+        let mut ttfb = None;
+        while let Some(data) = body.data().await {
+            match data {
+                Ok(data) => {
+                    ttfb.get_or_insert_with(|| sent.elapsed());
+                    bytes += data.len() as u64;
+                }
+                Err(_) => return Outcome::Failed { bytes },
+            }
+        }
+        if bytes < end - start + 1 {
+            return Outcome::Failed { bytes };
+        }
+        Outcome::Fetched { bytes, ttfb }
+    }
+}
+
+fn range_request(url: &Uri, start: u64, end: u64) -> Result<Request<Body>, Error> {
+    Ok(Request::get(url.clone())
+        .header(RANGE, format!("bytes={}-{}", start, end))
+        .body(Body::empty())?)
+}
+
+// The fastest setting that was never throttled and never failed. Fewer
+// concurrent requests, then smaller chunks, win among equally fast settings:
+// they hold less memory and are less likely to be throttled in longer use.
+pub fn recommend(results: &[BenchResult]) -> Option<&BenchResult> {
+    let clean: Vec<&BenchResult> = results.iter().filter(|result| result.is_clean()).collect();
+    let best = clean.iter().map(|result| result.throughput()).fold(0.0, f64::max);
+    clean
+        .into_iter()
+        .filter(|result| result.throughput() >= best * (1.0 - THROUGHPUT_TOLERANCE))
+        .min_by_key(|result| (result.concurrency, result.chunk_size))
+}
+
+// "512KB", "1MB", "2.5MB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[(&str, u64)] = &[("GB", 1 << 30), ("MB", 1 << 20), ("KB", 1 << 10)];
+    for (unit, size) in UNITS {
+        if bytes >= *size {
+            let value = bytes as f64 / *size as f64;
+            let value = format!("{:.1}", value);
+            return format!("{}{}", value.trim_end_matches(".0"), unit);
+        }
+    }
+    format!("{}B", bytes)
+}
@@ -0,0 +1,326 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use third_wheel::CertificateAuthority;
+use tokio::sync::Notify;
+
+use crate::config::{Config, ResolverKind};
+use crate::connector::IpFamily;
+use crate::download::{mitm, ParallelDownloadManager};
+use crate::har::HarRecorder;
+use crate::proxy::ProxyServer;
+use crate::ratelimit::RateLimiter;
+use crate::replay::Replay;
+use crate::stream_key::StreamKeyRules;
+use crate::Error;
+
+// Future that serves a bound proxy until it is shut down
+pub type ServerFuture = Pin<Box<dyn Future<Output = Result<(), hyper::Error>> + Send>>;
+
+// Builds the chunking proxy from a Config. The certificate authority is loaded
+// from the configured cert and key files unless one is supplied.
+pub struct ProxyBuilder {
+    config: Config,
+    ca: Option<CertificateAuthority>,
+    recorder: Option<HarRecorder>,
+    replay: Option<Replay>,
+}
+
+impl ProxyBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ca: None,
+            recorder: None,
+            replay: None,
+        }
+    }
+
+    // Sign intercepted connections with this CA instead of the configured files
+    pub fn certificate_authority(mut self, ca: CertificateAuthority) -> Self {
+        self.ca = Some(ca);
+        self
+    }
+
+    // Write each proxied exchange to a HAR file; it is complete once the
+    // proxy has shut down
+    pub fn record(mut self, recorder: HarRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    // Answer every intercepted request from a recording; nothing is fetched
+    // upstream
+    pub fn replay(mut self, replay: Replay) -> Self {
+        self.replay = Some(replay);
+        self
+    }
+
+    pub fn build(self) -> Result<Proxy, Error> {
+        let config = self.config;
+
+        config.validate()?;
+
+        // What the proxy will do, for Proxy::describe
+        let mut summary = Vec::new();
+        let site_policies = config.get_site_policies()?;
+        for (name, _, profile) in config.websites.enabled_sites() {
+            let Some(profile) = profile else {
+                continue;
+            };
+            let policy = site_policies.default_policy().with_profile(&name, profile);
+            summary.push(format!("Site profile {}: {}", name, policy.describe()));
+        }
+        let site_policies = Arc::new(site_policies);
+
+        let ca = match self.ca {
+            Some(ca) => ca,
+            None => CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
+                &config.proxy.cert_file,
+                &config.proxy.key_file,
+                &config.get_passphrase(),
+            )
+            .map_err(|e| {
+                format!(
+                    "Failed to load certificates from '{}' and '{}': {}",
+                    config.proxy.cert_file, config.proxy.key_file, e
+                )
+            })?,
+        };
+
+        // Initialize parallel download manager with memory pooling
+        let download_manager = ParallelDownloadManager::new(
+            config.proxy.max_concurrent_chunks,
+            config.proxy.parallel_downloads,
+            config.proxy.memory_pool_enabled,
+            config.proxy.memory_pool_budget,
+            config.proxy.coalesce_requests,
+            StreamKeyRules::new(&config.stream_keys),
+        )
+        .with_rate_limiter(RateLimiter::new(
+            config.bandwidth.global_limit,
+            config.bandwidth.per_stream_limit,
+        ))
+        .with_hedger(config.get_hedger());
+
+        let recorder = self.recorder.map(Arc::new);
+        if let Some(recorder) = &recorder {
+            summary.push(format!(
+                "Recording exchanges to {} (bodies: {})",
+                recorder.path().display(),
+                if recorder.records_bodies() { "yes" } else { "no" }
+            ));
+        }
+        let replay = self.replay.map(Arc::new);
+        if let Some(replay) = &replay {
+            summary.push(format!(
+                "Replay mode: answering from {} ({} resources, {} timing)",
+                replay.path().display(),
+                replay.resources(),
+                if replay.reproduces_timing() { "recorded" } else { "no" }
+            ));
+        }
+        let background = config.get_background_policy();
+        if background.is_some() {
+            summary.push(format!(
+                "Background download enabled: whole streams at up to {}, released after {}s idle",
+                config
+                    .background
+                    .rate_limit
+                    .map_or_else(|| "unlimited".to_string(), |r| format!("{}KB/s", r / 1024)),
+                config.background.idle_timeout
+            ));
+        }
+        let tee = config.get_tee()?;
+        if let Some(tee) = &tee {
+            summary.push(format!("Saving streams to {}", tee.dir().display()));
+        }
+        let download_manager = download_manager
+            .with_recorder(recorder.clone())
+            .with_replay(replay)
+            .with_tee(tee)
+            .with_background(background);
+
+        #[cfg(feature = "fault-injection")]
+        let download_manager = {
+            let faults = config.get_fault_injector();
+            if let Some(faults) = &faults {
+                summary.push(format!(
+                    "Fault injection enabled: seed {}, probability {}, every {} (after {} responses), kinds {:?}",
+                    faults.seed(),
+                    config.faults.probability,
+                    config.faults.every,
+                    config.faults.start_after,
+                    config.faults.kinds
+                ));
+            }
+            download_manager.with_faults(faults)
+        };
+        let download_manager = Arc::new(download_manager);
+
+        if config.hedging.enabled {
+            summary.push(format!(
+                "Hedged requests enabled: duplicate after p{} first-byte latency (min {}ms)",
+                config.hedging.percentile, config.hedging.min_delay_ms
+            ));
+        }
+
+        if config.bandwidth.global_limit.is_some() || config.bandwidth.per_stream_limit.is_some() {
+            let describe = |limit: Option<u64>| {
+                limit.map_or_else(|| "unlimited".to_string(), |r| format!("{}KB/s", r / 1024))
+            };
+            summary.push(format!(
+                "Bandwidth limits: global {}, per stream {}",
+                describe(config.bandwidth.global_limit),
+                describe(config.bandwidth.per_stream_limit)
+            ));
+        }
+
+        if config.proxy.parallel_downloads {
+            summary.push(format!(
+                "Parallel downloads enabled: max {} concurrent chunks, {}MB prefetch buffer",
+                config.proxy.max_concurrent_chunks,
+                config.proxy.prefetch_ahead / 1024 / 1024
+            ));
+        }
+
+        if config.proxy.memory_pool_enabled {
+            summary.push(format!(
+                "Memory pool enabled: up to {}MB of chunk buffers",
+                config.proxy.memory_pool_budget / 1024 / 1024
+            ));
+        }
+
+        let network = config.get_network_options();
+        if network.family != IpFamily::Any
+            || network.source_address.is_some()
+            || network.interface.is_some()
+        {
+            summary.push(format!(
+                "Outbound network: family {:?}, source address {}, interface {}",
+                network.family,
+                network
+                    .source_address
+                    .map_or_else(|| "default".to_string(), |a| a.to_string()),
+                network.interface.as_deref().unwrap_or("default")
+            ));
+        }
+
+        if !config.dns.hosts.is_empty() {
+            summary.push(format!("DNS overrides: {} static host entries", config.dns.hosts.len()));
+        }
+
+        if let Some(upstream) = config.get_upstream_proxy()? {
+            summary.push(format!("Upstream proxy: {}", upstream.display_url()));
+        }
+        if config.dns.resolver == ResolverKind::Doh {
+            summary.push(format!(
+                "DNS-over-HTTPS resolver: {} (fallback to system DNS: {})",
+                config.dns.doh_url.as_deref().unwrap_or("unset"),
+                config.dns.doh_fallback
+            ));
+        }
+        let connector = config.get_connector()?;
+
+        let policies = site_policies.clone();
+        let mut server = ProxyServer::new(
+            ca,
+            connector,
+            config.performance.connection_pool_size as usize,
+            move |req, client| mitm(req, client, policies.clone(), download_manager.clone()),
+        )
+        .map_err(|e| format!("Failed to initialize TLS: {}", e))?;
+
+        if config.proxy.selective_mitm {
+            summary.push("Selective MITM enabled: hosts outside [websites] rules are tunnelled unmodified".to_string());
+            let rules = site_policies;
+            server = server.intercept_if(move |host| rules.matches(host, None));
+        }
+
+        Ok(Proxy {
+            server,
+            port: config.proxy.port,
+            recorder,
+            summary,
+        })
+    }
+}
+
+// A configured proxy, ready to be bound to a listening address
+pub struct Proxy {
+    server: ProxyServer,
+    port: u16,
+    recorder: Option<Arc<HarRecorder>>,
+    summary: Vec<String>,
+}
+
+impl Proxy {
+    // One line per enabled feature and its settings, for a startup message.
+    // The proxy itself prints nothing.
+    pub fn describe(&self) -> &[String] {
+        &self.summary
+    }
+
+    // 127.0.0.1 on the configured port
+    pub fn default_addr(&self) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], self.port))
+    }
+
+    // Start listening. Port 0 picks a free port; see BoundProxy::local_addr.
+    pub fn bind(self, addr: SocketAddr) -> Result<BoundProxy, Error> {
+        let shutdown = ShutdownHandle::default();
+        let signal = shutdown.notify.clone();
+        let (local_addr, server) = self
+            .server
+            .bind(addr, async move { signal.notified().await })
+            .map_err(|e| format!("Failed to bind to '{}': {}", addr, e))?;
+
+        Ok(BoundProxy {
+            local_addr,
+            shutdown,
+            server: Box::pin(server),
+            recorder: self.recorder,
+        })
+    }
+}
+
+// A proxy listening on a socket; `run` serves it until shutdown
+pub struct BoundProxy {
+    local_addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    server: ServerFuture,
+    recorder: Option<Arc<HarRecorder>>,
+}
+
+impl BoundProxy {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Completes once shut down and the open connections have finished
+    pub async fn run(self) -> Result<(), hyper::Error> {
+        let result = self.server.await;
+        if let Some(recorder) = &self.recorder {
+            recorder.flush();
+        }
+        result
+    }
+}
+
+// Stops a running proxy from anywhere; cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    notify: Arc<Notify>,
+}
+
+impl ShutdownHandle {
+    // Stop accepting connections. Safe to call before `run` or more than once.
+    pub fn shutdown(&self) {
+        self.notify.notify_one();
+    }
+}
@@ -0,0 +1,1118 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::connector::{Connector, NetworkOptions, UpstreamProxy};
+use crate::dns::{DohResolver, Resolver};
+use crate::download::BackgroundPolicy;
+#[cfg(feature = "fault-injection")]
+use crate::faults::{FaultInjector, FaultSchedule};
+use crate::hedge::Hedger;
+use crate::inflight::RetryPolicy;
+use crate::pool;
+use crate::proxy::build_client;
+use crate::sites::{split_target, ChunkPolicy, SitePattern, SitePolicies, BUILTIN_SITES};
+use crate::tee::Tee;
+
+// Types of public config fields
+pub use crate::connector::IpFamily;
+#[cfg(feature = "fault-injection")]
+pub use crate::faults::FaultKind;
+pub use crate::sites::{SiteProfile, SiteSetting};
+
+// Custom deserializer for human-readable sizes (e.g., "10MB", "50MB", "1GB")
+pub fn deserialize_size<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SizeValue {
+        Number(u64),
+        String(String),
+    }
+
+    let value = SizeValue::deserialize(deserializer)?;
+
+    match value {
+        SizeValue::Number(n) => Ok(n),
+        SizeValue::String(s) => parse_size(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+// Optional size for settings that fall back to another value when unset
+pub fn deserialize_optional_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SizeValue {
+        Number(u64),
+        String(String),
+    }
+
+    match Option::<SizeValue>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(SizeValue::Number(n)) => Ok(Some(n)),
+        Some(SizeValue::String(s)) => parse_size(&s).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+// Optional rate such as "8MB/s" (bytes per second); 0 or unset means unlimited
+pub fn deserialize_rate<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RateValue {
+        Number(u64),
+        String(String),
+    }
+
+    let rate = match Option::<RateValue>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(RateValue::Number(n)) => n,
+        Some(RateValue::String(s)) => parse_rate(&s).map_err(serde::de::Error::custom)?,
+    };
+    Ok(Some(rate).filter(|r| *r > 0))
+}
+
+pub fn parse_rate(input: &str) -> Result<u64, String> {
+    let trimmed = input.trim();
+    let size = trimmed
+        .strip_suffix("/s")
+        .or_else(|| trimmed.strip_suffix("/S"))
+        .unwrap_or(trimmed);
+    parse_size(size).map_err(|_| {
+        format!(
+            "Invalid rate format: {}. Use formats like '500KB/s', '8MB/s'",
+            input
+        )
+    })
+}
+
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim().to_uppercase();
+
+    if let Ok(num) = input.parse::<u64>() {
+        return Ok(num);
+    }
+
+    // More efficient unit parsing with compile-time constants
+    const UNITS: &[(&str, u64)] = &[
+        ("TB", 1_024_u64.pow(4)),
+        ("GB", 1_024_u64.pow(3)),
+        ("MB", 1_024_u64.pow(2)),
+        ("KB", 1_024),
+        ("T", 1_024_u64.pow(4)),
+        ("G", 1_024_u64.pow(3)),
+        ("M", 1_024_u64.pow(2)),
+        ("K", 1_024),
+    ];
+
+    for (unit, multiplier) in UNITS {
+        if let Some(number_str) = input.strip_suffix(unit) {
+            let number: f64 = number_str
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid number in size: {}", input))?;
+
+            return Ok((number * *multiplier as f64) as u64);
+        }
+    }
+
+    Err(format!(
+        "Invalid size format: {}. Use formats like '10MB', '50MB', '1GB'",
+        input
+    ))
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub performance: PerformanceConfig,
+    #[serde(default)]
+    pub websites: WebsitesConfig,
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub dns: DnsConfig,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default)]
+    pub hedging: HedgingConfig,
+    #[serde(default)]
+    pub background: BackgroundConfig,
+    #[serde(default)]
+    pub tee: TeeConfig,
+    #[cfg(feature = "fault-injection")]
+    #[serde(default)]
+    pub faults: FaultsConfig,
+    // Host domain -> query parameters that identify the media (see stream_key.rs)
+    #[serde(default)]
+    pub stream_keys: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProxyConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_chunk_size", deserialize_with = "deserialize_size")]
+    pub chunk_size: u64,
+    #[serde(default = "default_cert_file")]
+    pub cert_file: String,
+    #[serde(default = "default_key_file")]
+    pub key_file: String,
+    #[serde(default)]
+    pub adaptive_chunking: bool,
+    #[serde(
+        default = "default_min_chunk_size",
+        deserialize_with = "deserialize_size"
+    )]
+    pub min_chunk_size: u64,
+    #[serde(
+        default = "default_max_chunk_size",
+        deserialize_with = "deserialize_size"
+    )]
+    pub max_chunk_size: u64,
+    #[serde(default)]
+    pub parallel_downloads: bool,
+    #[serde(default = "default_max_concurrent_chunks")]
+    pub max_concurrent_chunks: u32,
+    #[serde(
+        default = "default_prefetch_ahead",
+        deserialize_with = "deserialize_size"
+    )]
+    pub prefetch_ahead: u64,
+    #[serde(default = "default_memory_pool_enabled")]
+    pub memory_pool_enabled: bool,
+    #[serde(
+        default = "default_memory_pool_budget",
+        deserialize_with = "deserialize_size"
+    )]
+    pub memory_pool_budget: u64,
+    #[serde(default = "default_selective_mitm")]
+    pub selective_mitm: bool,
+    #[serde(default = "default_coalesce_requests")]
+    pub coalesce_requests: bool,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SecurityConfig {
+    pub passphrase: Option<String>,
+    #[serde(default = "default_cert_validity_days")]
+    pub cert_validity_days: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LoggingConfig {
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    pub log_file: Option<String>,
+    #[serde(default)]
+    pub log_timing: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PerformanceConfig {
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    #[serde(default = "default_connection_pool_size")]
+    pub connection_pool_size: u32,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebsitesConfig {
+    #[serde(default = "default_youtube")]
+    pub youtube: SiteSetting,
+    #[serde(default = "default_youtube_alternatives")]
+    pub youtube_alternatives: SiteSetting,
+    #[serde(default = "default_site_disabled")]
+    pub vimeo: SiteSetting,
+    #[serde(default = "default_site_disabled")]
+    pub dailymotion: SiteSetting,
+    #[serde(default = "default_site_disabled")]
+    pub twitch: SiteSetting,
+    #[serde(default)]
+    pub custom_domains: Vec<String>,
+    // [websites.profiles."example.com"] profiles for entries in custom_domains
+    #[serde(default)]
+    pub profiles: HashMap<String, SiteProfile>,
+}
+
+impl WebsitesConfig {
+    pub fn builtin(&self, name: &str) -> &SiteSetting {
+        match name {
+            "youtube" => &self.youtube,
+            "youtube_alternatives" => &self.youtube_alternatives,
+            "vimeo" => &self.vimeo,
+            "dailymotion" => &self.dailymotion,
+            "twitch" => &self.twitch,
+            _ => unreachable!("not a built-in site: {}", name),
+        }
+    }
+
+    // (name, site patterns, profile) of every enabled site
+    pub fn enabled_sites(&self) -> Vec<(String, Vec<String>, Option<&SiteProfile>)> {
+        let builtin = BUILTIN_SITES.iter().filter_map(|(name, domains)| {
+            let setting = self.builtin(name);
+            setting.enabled().then(|| {
+                let domains = domains.iter().map(|d| d.to_string()).collect();
+                (name.to_string(), domains, setting.profile())
+            })
+        });
+        let custom = self.custom_domains.iter().filter_map(|domain| {
+            let profile = self.profiles.get(domain);
+            let enabled = profile.and_then(|p| p.enabled).unwrap_or(true);
+            enabled.then(|| (domain.clone(), vec![domain.clone()], profile))
+        });
+        builtin.chain(custom).collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct UpstreamConfig {
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct NetworkConfig {
+    #[serde(default)]
+    pub ip_family: IpFamily,
+    pub source_address: Option<IpAddr>,
+    pub interface: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DnsConfig {
+    #[serde(default)]
+    pub resolver: ResolverKind,
+    pub doh_url: Option<String>,
+    #[serde(default = "default_doh_fallback")]
+    pub doh_fallback: bool,
+    #[serde(default = "default_dns_cache_ttl")]
+    pub cache_ttl: u64,
+    #[serde(default)]
+    pub hosts: HashMap<String, HostAddresses>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct BandwidthConfig {
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    pub global_limit: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    pub per_stream_limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HedgingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_hedge_percentile")]
+    pub percentile: f64,
+    #[serde(default = "default_hedge_min_delay_ms")]
+    pub min_delay_ms: u64,
+    #[serde(default = "default_hedge_default_delay_ms")]
+    pub default_delay_ms: u64,
+    #[serde(default = "default_hedge_alternate_nodes")]
+    pub alternate_nodes: bool,
+    #[serde(default)]
+    pub hosts: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BackgroundConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_background_rate_limit", deserialize_with = "deserialize_rate")]
+    pub rate_limit: Option<u64>,
+    #[serde(default = "default_background_idle_timeout")]
+    pub idle_timeout: u64,
+    #[serde(default = "default_background_max_cached", deserialize_with = "deserialize_size")]
+    pub max_cached: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TeeConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_tee_dir")]
+    pub dir: String,
+}
+
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FaultsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub probability: f64,
+    #[serde(default)]
+    pub every: u64,
+    #[serde(default)]
+    pub start_after: u64,
+    #[serde(default = "default_fault_kinds")]
+    pub kinds: Vec<FaultKind>,
+    #[serde(default = "default_drip_rate", deserialize_with = "deserialize_rate")]
+    pub drip_rate: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolverKind {
+    #[default]
+    System,
+    Doh,
+}
+
+// A static host override may pin a single address or a list of them
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum HostAddresses {
+    One(IpAddr),
+    Many(Vec<IpAddr>),
+}
+
+// Constants for better performance and maintainability
+pub const DEFAULT_PORT: u16 = 12081;  // Standard proxy port for mpv-http-ytproxy
+const DEFAULT_CHUNK_SIZE: u64 = 10 * 1024 * 1024; // 10MB
+const DEFAULT_MIN_CHUNK_SIZE: u64 = 2_621_440; // 2.5MB
+const DEFAULT_MAX_CHUNK_SIZE: u64 = 40 * 1024 * 1024; // 40MB
+const DEFAULT_CERT_VALIDITY_DAYS: u32 = 365;
+const DEFAULT_CONNECTION_POOL_SIZE: u32 = 10;
+const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_CONCURRENT_CHUNKS: u32 = 10;
+const DEFAULT_PREFETCH_AHEAD: u64 = 20 * 1024 * 1024; // 20MB
+const DEFAULT_DNS_CACHE_TTL: u64 = 300; // seconds
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 250;
+const DEFAULT_HEDGE_PERCENTILE: f64 = 95.0;
+const DEFAULT_HEDGE_MIN_DELAY_MS: u64 = 250;
+const DEFAULT_HEDGE_DEFAULT_DELAY_MS: u64 = 1000;
+const DEFAULT_BACKGROUND_RATE_LIMIT: u64 = 2 * 1024 * 1024; // bytes per second
+const DEFAULT_BACKGROUND_IDLE_TIMEOUT: u64 = 60; // seconds
+const DEFAULT_BACKGROUND_MAX_CACHED: u64 = 256 * 1024 * 1024; // 256MB, a quarter of the default pool budget
+const DEFAULT_TEE_DIR: &str = "saved-streams";
+const DEFAULT_PASSPHRASE: &str = "third-wheel";
+#[cfg(feature = "fault-injection")]
+const DEFAULT_DRIP_RATE: u64 = 64 * 1024; // bytes per second
+
+// Default value functions using constants (with inlining for better performance)
+#[inline]
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+#[inline]
+fn default_chunk_size() -> u64 {
+    DEFAULT_CHUNK_SIZE
+}
+#[inline]
+fn default_cert_file() -> String {
+    "cert.pem".to_string()
+}
+#[inline]
+fn default_key_file() -> String {
+    "key.pem".to_string()
+}
+#[inline]
+fn default_min_chunk_size() -> u64 {
+    DEFAULT_MIN_CHUNK_SIZE
+}
+#[inline]
+fn default_max_chunk_size() -> u64 {
+    DEFAULT_MAX_CHUNK_SIZE
+}
+#[inline]
+fn default_cert_validity_days() -> u32 {
+    DEFAULT_CERT_VALIDITY_DAYS
+}
+#[inline]
+fn default_log_level() -> String {
+    "info".to_string()
+}
+#[inline]
+fn default_connection_pool_size() -> u32 {
+    DEFAULT_CONNECTION_POOL_SIZE
+}
+#[inline]
+fn default_request_timeout() -> u64 {
+    DEFAULT_REQUEST_TIMEOUT
+}
+#[inline]
+fn default_http2() -> bool {
+    true
+} // Enable HTTP/2 by default for better performance
+#[inline]
+fn default_max_concurrent_chunks() -> u32 {
+    DEFAULT_MAX_CONCURRENT_CHUNKS
+}
+#[inline]
+fn default_prefetch_ahead() -> u64 {
+    DEFAULT_PREFETCH_AHEAD
+}
+#[inline]
+fn default_memory_pool_enabled() -> bool {
+    true
+} // Enable memory pooling by default
+#[inline]
+fn default_memory_pool_budget() -> u64 {
+    pool::DEFAULT_BUDGET
+}
+#[inline]
+fn default_selective_mitm() -> bool {
+    true
+} // Tunnel hosts outside [websites] without decrypting them
+
+#[inline]
+fn default_coalesce_requests() -> bool {
+    true
+} // Share in-flight upstream downloads between overlapping requests
+
+#[inline]
+fn default_retries() -> u32 {
+    DEFAULT_RETRIES
+}
+#[inline]
+fn default_retry_backoff_ms() -> u64 {
+    DEFAULT_RETRY_BACKOFF_MS
+}
+
+#[inline]
+fn default_dns_cache_ttl() -> u64 {
+    DEFAULT_DNS_CACHE_TTL
+}
+#[inline]
+fn default_doh_fallback() -> bool {
+    true
+} // Fall back to system DNS when the DoH server is unreachable
+
+#[inline]
+fn default_hedge_percentile() -> f64 {
+    DEFAULT_HEDGE_PERCENTILE
+}
+#[inline]
+fn default_hedge_min_delay_ms() -> u64 {
+    DEFAULT_HEDGE_MIN_DELAY_MS
+}
+#[inline]
+fn default_hedge_default_delay_ms() -> u64 {
+    DEFAULT_HEDGE_DEFAULT_DELAY_MS
+}
+#[inline]
+fn default_hedge_alternate_nodes() -> bool {
+    true
+} // Send googlevideo hedges to another node listed in the URL
+
+#[inline]
+fn default_background_rate_limit() -> Option<u64> {
+    Some(DEFAULT_BACKGROUND_RATE_LIMIT)
+}
+#[inline]
+fn default_background_idle_timeout() -> u64 {
+    DEFAULT_BACKGROUND_IDLE_TIMEOUT
+}
+#[inline]
+fn default_background_max_cached() -> u64 {
+    DEFAULT_BACKGROUND_MAX_CACHED
+}
+
+#[inline]
+fn default_tee_dir() -> String {
+    DEFAULT_TEE_DIR.to_string()
+}
+
+#[cfg(feature = "fault-injection")]
+#[inline]
+fn default_fault_kinds() -> Vec<FaultKind> {
+    FaultKind::ALL.to_vec()
+}
+
+#[cfg(feature = "fault-injection")]
+#[inline]
+fn default_drip_rate() -> Option<u64> {
+    Some(DEFAULT_DRIP_RATE)
+}
+
+#[inline]
+fn default_youtube() -> SiteSetting {
+    SiteSetting::Enabled(true)
+} // Enable YouTube by default
+
+#[inline]
+fn default_youtube_alternatives() -> SiteSetting {
+    SiteSetting::Enabled(true)
+} // Enable YouTube alternatives by default
+
+#[inline]
+fn default_site_disabled() -> SiteSetting {
+    SiteSetting::Enabled(false)
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            port: default_port(),
+            chunk_size: default_chunk_size(),
+            cert_file: default_cert_file(),
+            key_file: default_key_file(),
+            adaptive_chunking: false,
+            min_chunk_size: default_min_chunk_size(),
+            max_chunk_size: default_max_chunk_size(),
+            parallel_downloads: false,
+            max_concurrent_chunks: default_max_concurrent_chunks(),
+            prefetch_ahead: default_prefetch_ahead(),
+            memory_pool_enabled: default_memory_pool_enabled(),
+            memory_pool_budget: default_memory_pool_budget(),
+            selective_mitm: default_selective_mitm(),
+            coalesce_requests: default_coalesce_requests(),
+            retries: default_retries(),
+            retry_backoff_ms: default_retry_backoff_ms(),
+        }
+    }
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            passphrase: None,
+            cert_validity_days: default_cert_validity_days(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            log_file: None,
+            log_timing: false,
+        }
+    }
+}
+
+impl Default for PerformanceConfig {
+    fn default() -> Self {
+        Self {
+            http2: default_http2(),
+            connection_pool_size: default_connection_pool_size(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            resolver: ResolverKind::System,
+            doh_url: None,
+            doh_fallback: default_doh_fallback(),
+            cache_ttl: default_dns_cache_ttl(),
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+impl Default for HedgingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            percentile: default_hedge_percentile(),
+            min_delay_ms: default_hedge_min_delay_ms(),
+            default_delay_ms: default_hedge_default_delay_ms(),
+            alternate_nodes: default_hedge_alternate_nodes(),
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+impl Default for BackgroundConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rate_limit: default_background_rate_limit(),
+            idle_timeout: default_background_idle_timeout(),
+            max_cached: default_background_max_cached(),
+        }
+    }
+}
+
+impl Default for TeeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: default_tee_dir(),
+        }
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+impl Default for FaultsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            seed: None,
+            probability: 0.0,
+            every: 0,
+            start_after: 0,
+            kinds: default_fault_kinds(),
+            drip_rate: default_drip_rate(),
+        }
+    }
+}
+
+impl Default for WebsitesConfig {
+    fn default() -> Self {
+        Self {
+            youtube: default_youtube(),
+            youtube_alternatives: default_youtube_alternatives(),
+            vimeo: default_site_disabled(),
+            dailymotion: default_site_disabled(),
+            twitch: default_site_disabled(),
+            custom_domains: Vec::new(),
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, crate::Error> {
+        let content = fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&content)?;
+
+        #[cfg(not(feature = "fault-injection"))]
+        if toml::from_str::<toml::Table>(&content).is_ok_and(|table| table.contains_key("faults")) {
+            eprintln!("Warning: [faults] is ignored; it needs a build with --features fault-injection");
+        }
+        Ok(config)
+    }
+
+    // Settings that would stop the proxy from starting, apart from the
+    // certificate files
+    pub fn validate(&self) -> Result<(), String> {
+        if self.proxy.memory_pool_enabled && self.proxy.memory_pool_budget < pool::MIN_BUFFER_SIZE {
+            return Err(format!(
+                "Memory pool budget must be at least {} bytes, the smallest chunk buffer",
+                pool::MIN_BUFFER_SIZE
+            ));
+        }
+        self.check_chunk_size(self.proxy.chunk_size, "proxy")?;
+
+        if let Some(name) = self
+            .websites
+            .profiles
+            .keys()
+            .find(|name| !self.websites.custom_domains.contains(name))
+        {
+            return Err(format!(
+                "[websites.profiles.\"{}\"] has no matching entry in custom_domains",
+                name
+            ));
+        }
+        let site_policies = self.get_site_policies()?;
+        for (name, _, profile) in self.websites.enabled_sites() {
+            let Some(profile) = profile else {
+                continue;
+            };
+            let section = if self.websites.profiles.contains_key(&name) {
+                format!("websites.profiles.\"{}\"", name)
+            } else {
+                format!("websites.{}", name)
+            };
+            let chunk_size = site_policies.default_policy().with_profile(&name, profile).chunk_size;
+            self.check_chunk_size(chunk_size, &section)?;
+        }
+        self.get_connector()?;
+        Ok(())
+    }
+
+    // Every chunk needs one buffer, which has to fit in the memory budget
+    fn check_chunk_size(&self, chunk_size: u64, section: &str) -> Result<(), String> {
+        if chunk_size == 0 {
+            return Err(format!("Chunk size for [{}] must be greater than 0", section));
+        }
+        if chunk_size > pool::MAX_BUFFER_SIZE {
+            return Err(format!(
+                "Chunk size for [{}] must be at most {} bytes",
+                section,
+                pool::MAX_BUFFER_SIZE
+            ));
+        }
+        let buffer = usize::try_from(chunk_size).ok().and_then(pool::buffer_size);
+        if self.proxy.memory_pool_enabled && buffer.is_none_or(|size| size as u64 > self.proxy.memory_pool_budget) {
+            return Err(format!(
+                "Chunk size for [{}] must fit in the memory pool budget of {} bytes",
+                section, self.proxy.memory_pool_budget
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn test_url_support(&self, url: &str) -> Result<bool, String> {
+        let (host, path) = split_target(url);
+        Ok(self.get_site_policies()?.matches(&host, path.as_deref()))
+    }
+
+    // Host rules and chunking policy of every enabled site, on top of the
+    // [proxy] defaults
+    pub fn get_site_policies(&self) -> Result<SitePolicies, String> {
+        let default = ChunkPolicy {
+            site: "default".to_string(),
+            chunk_size: self.proxy.chunk_size,
+            prefetch_ahead: self.proxy.prefetch_ahead,
+            max_concurrent_chunks: self.proxy.max_concurrent_chunks,
+            retry: RetryPolicy {
+                attempts: self.proxy.retries,
+                backoff: Duration::from_millis(self.proxy.retry_backoff_ms),
+            },
+        };
+
+        let mut policies = SitePolicies::new(default.clone());
+        for (name, patterns, profile) in self.websites.enabled_sites() {
+            let patterns = patterns
+                .iter()
+                .map(|pattern| SitePattern::parse(pattern))
+                .collect::<Result<Vec<_>, _>>()?;
+            let policy = default.with_profile(&name, profile.unwrap_or(&SiteProfile::default()));
+            policies.add(patterns, policy);
+        }
+        Ok(policies)
+    }
+
+    pub fn get_passphrase(&self) -> String {
+        let (_, passphrase) = self.passphrase_sources().swap_remove(0);
+        passphrase
+    }
+
+    // Where the passphrase for the CA key can come from, in order of
+    // precedence; the first one is used
+    pub(crate) fn passphrase_sources(&self) -> Vec<(&'static str, String)> {
+        let mut sources = Vec::new();
+        if let Some(passphrase) = &self.security.passphrase {
+            sources.push(("[security] passphrase (or -s)", passphrase.clone()));
+        }
+        if let Ok(passphrase) = env::var("YTPROXY_PASSPHRASE") {
+            sources.push(("YTPROXY_PASSPHRASE", passphrase));
+        }
+        sources.push(("the default passphrase", DEFAULT_PASSPHRASE.to_string()));
+        sources
+    }
+
+    pub(crate) fn get_upstream_proxy(&self) -> Result<Option<UpstreamProxy>, String> {
+        match self.upstream.url.as_deref() {
+            Some(url) if !url.trim().is_empty() => UpstreamProxy::parse(
+                url.trim(),
+                self.upstream.username.as_deref(),
+                self.upstream.password.as_deref(),
+            )
+            .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // `connector` carries the network and upstream settings DoH queries are sent with
+    pub(crate) fn get_resolver(&self, connector: &Connector) -> Result<Resolver, String> {
+        let overrides: HashMap<String, Vec<IpAddr>> = self
+            .dns
+            .hosts
+            .iter()
+            .map(|(host, addrs)| {
+                let addrs = match addrs {
+                    HostAddresses::One(ip) => vec![*ip],
+                    HostAddresses::Many(ips) => ips.clone(),
+                };
+                (host.clone(), addrs)
+            })
+            .collect();
+        let cache_ttl = Duration::from_secs(self.dns.cache_ttl);
+
+        if self.dns.resolver == ResolverKind::System {
+            return Ok(Resolver::new(overrides, cache_ttl));
+        }
+
+        let url = self
+            .dns
+            .doh_url
+            .as_deref()
+            .ok_or("resolver = \"doh\" requires doh_url in [dns]")?;
+        let url = url
+            .parse()
+            .map_err(|e| format!("Invalid doh_url '{}': {}", url, e))?;
+
+        // The DoH server's own hostname is looked up with the system resolver
+        let bootstrap = connector
+            .clone()
+            .with_resolver(Resolver::new(overrides.clone(), cache_ttl));
+        let client = build_client(bootstrap, 2)
+            .map_err(|e| format!("Failed to initialize TLS for DoH: {}", e))?;
+
+        Ok(Resolver::new(overrides, cache_ttl)
+            .with_doh(DohResolver::new(url, client, self.dns.doh_fallback)))
+    }
+
+    pub(crate) fn get_hedger(&self) -> Option<Hedger> {
+        if !self.hedging.enabled {
+            return None;
+        }
+        Some(Hedger::new(
+            self.hedging.percentile,
+            Duration::from_millis(self.hedging.min_delay_ms),
+            Duration::from_millis(self.hedging.default_delay_ms),
+            self.hedging.alternate_nodes,
+            self.hedging.hosts.clone(),
+        ))
+    }
+
+    pub(crate) fn get_background_policy(&self) -> Option<BackgroundPolicy> {
+        if !self.background.enabled {
+            return None;
+        }
+        // One stream must not take the whole pool
+        let max_cached = if self.proxy.memory_pool_enabled {
+            self.background.max_cached.min(self.proxy.memory_pool_budget)
+        } else {
+            self.background.max_cached
+        };
+        Some(BackgroundPolicy::new(
+            self.background.rate_limit,
+            Duration::from_secs(self.background.idle_timeout),
+            max_cached,
+        ))
+    }
+
+    pub(crate) fn get_tee(&self) -> Result<Option<Tee>, String> {
+        if !self.tee.enabled {
+            return Ok(None);
+        }
+        let tee = Tee::new(&self.tee.dir)
+            .map_err(|e| format!("Cannot save streams to '{}': {}", self.tee.dir, e))?;
+        Ok(Some(tee))
+    }
+
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn get_fault_injector(&self) -> Option<FaultInjector> {
+        if !self.faults.enabled {
+            return None;
+        }
+        Some(FaultInjector::new(FaultSchedule {
+            seed: self.faults.seed,
+            probability: self.faults.probability.clamp(0.0, 1.0),
+            every: self.faults.every,
+            start_after: self.faults.start_after,
+            kinds: self.faults.kinds.clone(),
+            drip_rate: self.faults.drip_rate.unwrap_or(DEFAULT_DRIP_RATE),
+        }))
+    }
+
+    pub(crate) fn get_network_options(&self) -> NetworkOptions {
+        NetworkOptions {
+            family: self.network.ip_family,
+            source_address: self.network.source_address,
+            interface: self.network.interface.clone(),
+        }
+    }
+
+    // Connector for upstream requests: network options, upstream proxy and resolver
+    pub fn get_connector(&self) -> Result<Connector, String> {
+        let mut connector = Connector::new().with_network(self.get_network_options());
+        if let Some(upstream) = self.get_upstream_proxy()? {
+            connector = connector.with_upstream(upstream);
+        }
+        let resolver = self.get_resolver(&connector)?;
+        Ok(connector.with_resolver(resolver))
+    }
+
+    // Commented example configuration with every section and its defaults
+    pub fn example_config() -> &'static str {
+        r#"# mpv-http-ytproxy configuration file
+# Performance-optimized configuration with human-readable sizes, parallel downloads, and memory pooling
+
+[proxy]
+port = 12081
+chunk_size = "10MB"          # Default: 10MB for optimal balance
+cert_file = "cert.pem"
+key_file = "key.pem"
+adaptive_chunking = false
+min_chunk_size = "2.5MB"     # Minimum chunk size
+max_chunk_size = "40MB"      # Maximum chunk size
+
+# Parallel Download Settings (v0.5.0+)
+parallel_downloads = false   # Enable intelligent prefetching (requires coalesce_requests)
+max_concurrent_chunks = 10   # Max parallel chunk downloads
+prefetch_ahead = "20MB"      # Prefetch buffer size
+
+# Memory Pool Settings (v0.6.0+)
+memory_pool_enabled = true   # Enable buffer reuse for better performance
+memory_pool_budget = "1GB"   # Chunk data held at once; downloads wait for memory beyond it
+
+# Request Coalescing (v0.7.0+)
+coalesce_requests = true     # Overlapping requests share one in-flight upstream download
+
+# Retries (v0.7.0+)
+retries = 2                  # Repeat a chunk request after a connection error, 5xx or 429
+retry_backoff_ms = 250       # Wait before the first retry, doubled after each
+
+# Selective MITM (v0.7.0+)
+selective_mitm = true        # Only decrypt hosts matched by [websites]; tunnel everything else
+
+[security]
+cert_validity_days = 365
+
+[logging]
+level = "info"
+log_timing = false
+
+[performance]
+http2 = true                 # HTTP/2 enabled by default for better performance
+connection_pool_size = 10
+request_timeout = 30
+
+# Supported Websites Configuration
+[websites]
+# Enable/disable proxy for specific website categories
+youtube = true               # YouTube (youtube.com, youtu.be, googlevideo.com)
+youtube_alternatives = true  # Yewtu.be, Invidious, Piped
+vimeo = false               # Vimeo.com, vimeocdn.com
+dailymotion = false         # Dailymotion.com, dmcdn.net
+twitch = false              # Twitch.tv, ttvnw.net
+custom_domains = []         # Add custom domains: ["example.com", "video.site.com"]
+# Custom domain entries match whole hosts, not substrings:
+#   "example.com"          that host only
+#   "*.example.com"        any subdomain of example.com
+#   "example.com/videos/"  that host, paths starting with /videos/
+#   "re:^cdn[0-9]+\\.example\\.com$"  regex against the host
+
+# Per-Site Profiles (v0.7.0+)
+# Replace `site = true` with a table to override [proxy] settings for that site.
+# Custom domains take a [websites.profiles] table keyed by the domain as listed
+# in custom_domains.
+# [websites.vimeo]
+# chunk_size = "5MB"
+# prefetch_ahead = "10MB"
+# max_concurrent_chunks = 4
+# retries = 4
+# retry_backoff_ms = 500
+#
+# [websites.profiles."video.site.com"]
+# chunk_size = "2MB"
+# prefetch_ahead = "4MB"
+
+# Upstream Proxy (v0.7.0+)
+# Chain all outbound connections through a parent proxy
+[upstream]
+# url = "http://proxy.example.com:3128"    # HTTP CONNECT proxy
+# url = "socks5h://127.0.0.1:9050"         # SOCKS5 (socks5h = remote DNS, e.g. Tor)
+# username = "user"
+# password = "secret"
+
+# Outbound Network Settings (v0.7.0+)
+[network]
+ip_family = "any"            # any, prefer-ipv4, prefer-ipv6, ipv4, ipv6
+# source_address = "192.168.1.10"  # Bind outbound sockets to this local IP
+# interface = "eth0"               # Bind outbound sockets to this interface (Linux only)
+
+# DNS Settings (v0.7.0+)
+[dns]
+resolver = "system"          # system or doh
+# doh_url = "https://1.1.1.1/dns-query"  # RFC 8484 endpoint used when resolver = "doh"
+doh_fallback = true          # Use system DNS if the DoH server fails
+cache_ttl = 300              # Seconds to cache lookups in-process (0 disables)
+
+[dns.hosts]
+# Static host overrides, e.g. to pin a known-good edge node or a local test origin
+# "rr1---sn-example.googlevideo.com" = "203.0.113.10"
+# "video.example.com" = ["127.0.0.1", "::1"]
+
+# Bandwidth Limits (v0.7.0+)
+# Caps on upstream download rate, e.g. to leave room for others on a shared connection
+[bandwidth]
+# global_limit = "8MB/s"       # All streams together
+# per_stream_limit = "4MB/s"   # Each stream, including its prefetches
+
+# Hedged Requests (v0.7.0+)
+# Duplicate a chunk request whose response is slower than usual; the first answer wins.
+# Needs coalesce_requests. Duplicates take a free max_concurrent_chunks slot or are skipped.
+[hedging]
+enabled = false
+percentile = 95              # Hedge when slower than this percentile of recent first-byte times
+min_delay_ms = 250           # Never hedge sooner than this
+default_delay_ms = 1000      # Threshold until enough latency samples exist
+alternate_nodes = true       # googlevideo: send the duplicate to another node from the URL's mn list
+
+[hedging.hosts]
+# Static alternate host for duplicates
+# "video.example.com" = "video-backup.example.com"
+
+# Background Download (v0.7.0+)
+# Once playback starts, download the rest of the stream in the background so a seek
+# anywhere is served from memory. Uses free max_concurrent_chunks slots after playback
+# and prefetch requests. Needs coalesce_requests; combine with [tee] to keep the file.
+[background]
+enabled = false
+rate_limit = "2MB/s"         # Cap for all background downloads together
+idle_timeout = 60            # Stop and release the cache after this many seconds without playback
+max_cached = "256MB"         # Memory held for one stream; the download stops there
+
+# Save Streams (v0.7.0+)
+# Write the media bytes that pass through the proxy into one file per stream (per itag
+# on YouTube). Unfinished files end in .part with a .part.ranges sidecar; a file is
+# renamed to its final name once every byte has been played or prefetched.
+[tee]
+enabled = false
+dir = "saved-streams"        # Relative to the proxy's working directory
+
+# Stream Identity (v0.7.0+)
+# Query parameters that identify the same media across requests, per host domain.
+# googlevideo.com uses id, itag and clen by default; entries here add or override rules.
+[stream_keys]
+# "vimeocdn.com" = ["id", "quality"]
+
+# Fault Injection (v0.7.0+)
+# Debug builds only (cargo build --features fault-injection); ignored otherwise.
+# Breaks upstream responses on a schedule to exercise retries, prefetch and mpv.
+# [faults]
+# enabled = true
+# seed = 42                   # Same seed and request order give the same faults
+# probability = 0.1           # Chance that an upstream response is broken
+# every = 0                   # Also break every Nth response (0 = off)
+# start_after = 0             # Leave the first N responses alone
+# kinds = ["429", "5xx", "reset", "drip", "truncate"]
+# drip_rate = "64KB/s"        # Pace of "drip" bodies
+
+# Size Format Examples:
+# - Numbers: 1024, 10485760 
+# - With units: 10KB, 10MB, 1GB, 2TB
+# - Short units: 10K, 10M, 1G, 2T
+
+# Performance Features:
+# - Parallel Downloads: Enable for faster seeking and better buffering
+# - Memory Pool: Reuses buffers to reduce allocation overhead
+# - HTTP/2: Improved connection efficiency for YouTube streaming
+# - Adjust max_concurrent_chunks based on connection speed
+# - Increase prefetch_ahead for smoother playback
+"#
+    }
+}
@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use hyper::body::HttpBody;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Method, Request, StatusCode, Uri};
+
+use crate::proxy::UpstreamClient;
+
+// Upper bound on cached hostnames; expired entries are evicted first
+const MAX_CACHE_ENTRIES: usize = 1024;
+// How long a single DNS-over-HTTPS query may take before it counts as failed
+const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+// Largest DNS message accepted from a DoH server
+const MAX_DNS_MESSAGE: usize = 64 * 1024;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+// RFC 8484 DNS-over-HTTPS client. Queries go out through the proxy's own
+// connector, so upstream proxy and network settings apply to them as well.
+#[derive(Debug)]
+pub struct DohResolver {
+    url: Uri,
+    client: UpstreamClient,
+    fallback: bool,
+}
+
+impl DohResolver {
+    pub fn new(url: Uri, client: UpstreamClient, fallback: bool) -> Self {
+        Self {
+            url,
+            client,
+            fallback,
+        }
+    }
+
+    // Resolve A and AAAA records; returns the addresses and the smallest record TTL
+    async fn lookup(&self, host: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let (v4, v6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+
+        let mut addrs = Vec::new();
+        let mut ttl = None;
+        let mut last_error = None;
+        for result in [v4, v6] {
+            match result {
+                Ok((found, found_ttl)) if !found.is_empty() => {
+                    addrs.extend(found);
+                    ttl = Some(ttl.map_or(found_ttl, |t: u32| t.min(found_ttl)));
+                }
+                Ok(_) => {}
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("DoH: no address for {}", host))
+            }));
+        }
+        Ok((addrs, Duration::from_secs(ttl.unwrap_or(0) as u64)))
+    }
+
+    async fn query(&self, host: &str, qtype: u16) -> io::Result<(Vec<IpAddr>, u32)> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Body::from(encode_query(host, qtype)?))
+            .map_err(io::Error::other)?;
+
+        let exchange = async {
+            let response = self.client.request(request).await.map_err(io::Error::other)?;
+            if response.status() != StatusCode::OK {
+                return Err(io::Error::other(format!(
+                    "DoH server answered {}",
+                    response.status()
+                )));
+            }
+
+            let mut body = response.into_body();
+            let mut message = Vec::new();
+            while let Some(chunk) = body.data().await {
+                message.extend_from_slice(&chunk.map_err(io::Error::other)?);
+                if message.len() > MAX_DNS_MESSAGE {
+                    return Err(io::Error::other("DoH response too large"));
+                }
+            }
+            Ok(message)
+        };
+
+        let message = tokio::time::timeout(DOH_TIMEOUT, exchange)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DoH query timed out"))??;
+        parse_response(&message, qtype)
+    }
+}
+
+fn encode_query(host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    // ID 0 as recommended by RFC 8484, recursion desired, one question
+    let mut message = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid hostname for DNS query: {}", host),
+            ));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+fn parse_response(message: &[u8], qtype: u16) -> io::Result<(Vec<IpAddr>, u32)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed DNS response");
+    let read_u16 = |pos: usize| -> io::Result<u16> {
+        message
+            .get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(malformed)
+    };
+
+    if message.len() < 12 {
+        return Err(malformed());
+    }
+    let rcode = message[3] & 0x0f;
+    match rcode {
+        0 => {}
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "DNS name does not exist")),
+        _ => return Err(io::Error::other(format!("DNS server returned rcode {}", rcode))),
+    }
+
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos).ok_or_else(malformed)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(message, pos).ok_or_else(malformed)?;
+        let rtype = read_u16(pos)?;
+        let record_ttl = message
+            .get(pos + 4..pos + 8)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(malformed)?;
+        let rdlength = read_u16(pos + 8)? as usize;
+        let rdata = message
+            .get(pos + 10..pos + 10 + rdlength)
+            .ok_or_else(malformed)?;
+        pos += 10 + rdlength;
+
+        // CNAME records in the chain are skipped; the final A/AAAA records follow them
+        let addr = match (rtype, rdata.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => continue,
+        };
+        addrs.push(addr);
+        ttl = ttl.min(record_ttl);
+    }
+
+    Ok((addrs, if ttl == u32::MAX { 0 } else { ttl }))
+}
+
+// Returns the position just past a (possibly compressed) domain name
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+// Hostname resolver with static overrides and an in-process TTL cache in
+// front of the system resolver or, when configured, a DoH server
+#[derive(Debug)]
+pub struct Resolver {
+    overrides: HashMap<String, Vec<IpAddr>>,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    cache_ttl: Duration,
+    doh: Option<DohResolver>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new(HashMap::new(), Duration::ZERO)
+    }
+}
+
+impl Resolver {
+    // A zero TTL disables caching; overrides always apply
+    pub fn new(overrides: HashMap<String, Vec<IpAddr>>, cache_ttl: Duration) -> Self {
+        let overrides = overrides
+            .into_iter()
+            .map(|(host, addrs)| (host.to_lowercase(), addrs))
+            .collect();
+
+        Self {
+            overrides,
+            cache: Mutex::new(HashMap::new()),
+            cache_ttl,
+            doh: None,
+        }
+    }
+
+    pub fn with_doh(mut self, doh: DohResolver) -> Self {
+        self.doh = Some(doh);
+        self
+    }
+
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let with_port = |addrs: &[IpAddr]| addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let key = host.to_lowercase();
+        if let Some(addrs) = self.overrides.get(&key) {
+            return Ok(with_port(addrs));
+        }
+
+        if let Some(addrs) = self.cached(&key) {
+            return Ok(with_port(&addrs));
+        }
+
+        let (addrs, ttl) = match &self.doh {
+            Some(doh) => match doh.lookup(host).await {
+                // Record TTLs are honoured, capped at the configured cache TTL
+                Ok((addrs, ttl)) => (addrs, ttl.min(self.cache_ttl)),
+                Err(e) if doh.fallback => {
+                    eprintln!("Warning: DoH lookup for {} failed ({}), using system DNS", host, e);
+                    (system_lookup(host, port).await?, self.cache_ttl)
+                }
+                Err(e) => return Err(e),
+            },
+            None => (system_lookup(host, port).await?, self.cache_ttl),
+        };
+        self.store(key, addrs.clone(), ttl);
+        Ok(with_port(&addrs))
+    }
+
+    fn cached(&self, key: &str) -> Option<Vec<IpAddr>> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(key)
+            .filter(|entry| entry.expires > Instant::now())
+            .map(|entry| entry.addrs.clone())
+    }
+
+    fn store(&self, key: String, addrs: Vec<IpAddr>, ttl: Duration) {
+        if ttl.is_zero() || addrs.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            // Still full of live entries: drop the one closest to expiry
+            if let Some(oldest) = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(host, _)| host.clone())
+            {
+                cache.remove(&oldest);
+            }
+        }
+
+        cache.insert(
+            key,
+            CacheEntry {
+                addrs,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+async fn system_lookup(host: &str, port: u16) -> io::Result<Vec<IpAddr>> {
+    Ok(tokio::net::lookup_host((host, port))
+        .await?
+        .map(|addr| addr.ip())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Response to an A query for www.youtube.com: a CNAME followed by two A
+    // records whose names point into the CNAME's data
+    const YOUTUBE_A: &[u8] = &[
+        0x00, 0x00, 0x81, 0x80, 0x00, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
+        // Question: www.youtube.com A IN
+        3, b'w', b'w', b'w', 7, b'y', b'o', b'u', b't', b'u', b'b', b'e', 3, b'c', b'o', b'm', 0,
+        0x00, 0x01, 0x00, 0x01,
+        // www.youtube.com CNAME youtube-ui.l.google.com, TTL 300
+        0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x16,
+        10, b'y', b'o', b'u', b't', b'u', b'b', b'e', b'-', b'u', b'i', 1, b'l',
+        6, b'g', b'o', b'o', b'g', b'l', b'e', 0xc0, 0x18,
+        // youtube-ui.l.google.com A 142.250.185.110, TTL 180
+        0xc0, 0x2d, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0xb4, 0x00, 0x04,
+        142, 250, 185, 110,
+        // youtube-ui.l.google.com A 142.250.185.78, TTL 120
+        0xc0, 0x2d, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x04,
+        142, 250, 185, 78,
+    ];
+
+    #[test]
+    fn query_encoding() {
+        let query = encode_query("example.com.", TYPE_AAAA).unwrap();
+        assert_eq!(
+            query,
+            [
+                &[0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0][..],
+                &[7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0],
+                &[0, 28, 0, 1],
+            ]
+            .concat()
+        );
+        assert_eq!(query, encode_query("example.com", TYPE_AAAA).unwrap());
+
+        for host in ["", "a..b", &format!("{}.com", "x".repeat(64))] {
+            let e = encode_query(host, TYPE_A).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn answers_after_a_cname_chain() {
+        let (addrs, ttl) = parse_response(YOUTUBE_A, TYPE_A).unwrap();
+        assert_eq!(
+            addrs,
+            [
+                IpAddr::V4(Ipv4Addr::new(142, 250, 185, 110)),
+                IpAddr::V4(Ipv4Addr::new(142, 250, 185, 78)),
+            ]
+        );
+        // The CNAME's longer TTL does not count
+        assert_eq!(ttl, 120);
+
+        // Records of the other family are ignored
+        assert_eq!(parse_response(YOUTUBE_A, TYPE_AAAA).unwrap(), (vec![], 0));
+    }
+
+    #[test]
+    fn aaaa_answer() {
+        let mut message = YOUTUBE_A[..33].to_vec();
+        message[7] = 1;
+        message[31] = 28;
+        message.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x10]);
+        message.extend_from_slice(&[0x2a, 0x00, 0x14, 0x50, 0x40, 0x01, 0x08, 0x1a, 0, 0, 0, 0, 0, 0, 0x20, 0x0e]);
+
+        let (addrs, ttl) = parse_response(&message, TYPE_AAAA).unwrap();
+        assert_eq!(addrs, ["2a00:1450:4001:81a::200e".parse::<IpAddr>().unwrap()]);
+        assert_eq!(ttl, 60);
+    }
+
+    #[test]
+    fn error_rcodes() {
+        let mut message = YOUTUBE_A[..33].to_vec();
+        message[3] = 0x83;
+        message[7] = 0;
+        let e = parse_response(&message, TYPE_A).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        message[3] = 0x82;
+        let e = parse_response(&message, TYPE_A).unwrap_err();
+        assert!(e.to_string().contains("rcode 2"), "{}", e);
+    }
+
+    #[test]
+    fn truncated_or_malformed_messages_are_errors() {
+        // Cut anywhere, including inside the header, a name or a record
+        for len in 0..YOUTUBE_A.len() {
+            let e = parse_response(&YOUTUBE_A[..len], TYPE_A).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "cut at {}", len);
+        }
+
+        // More answers announced than present
+        let mut message = YOUTUBE_A.to_vec();
+        message[7] = 4;
+        assert!(parse_response(&message, TYPE_A).is_err());
+
+        // Record data running past the end of the message
+        let mut message = YOUTUBE_A.to_vec();
+        let last = message.len() - 5;
+        message[last] = 0xff;
+        assert!(parse_response(&message, TYPE_A).is_err());
+
+        // A label longer than the message
+        let mut message = YOUTUBE_A[..12].to_vec();
+        message[5] = 1;
+        message.extend_from_slice(&[63, b'a']);
+        assert!(parse_response(&message, TYPE_A).is_err());
+    }
+
+    #[tokio::test]
+    async fn overrides_bypass_lookup() {
+        let addr: IpAddr = [192, 0, 2, 7].into();
+        let resolver = Resolver::new(HashMap::from([("Video.Test".to_string(), vec![addr])]), Duration::ZERO);
+
+        // The name exists nowhere else; case doesn't matter
+        let found = resolver.lookup("VIDEO.test", 443).await.unwrap();
+        assert_eq!(found, [SocketAddr::new(addr, 443)]);
+        assert!(resolver.cache.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_refreshed() {
+        let stale: IpAddr = [192, 0, 2, 1].into();
+        let resolver = Resolver::new(HashMap::new(), Duration::from_secs(60));
+        resolver.store("localhost".to_string(), vec![stale], Duration::from_secs(60));
+        assert_eq!(resolver.lookup("localhost", 80).await.unwrap(), [SocketAddr::new(stale, 80)]);
+
+        resolver.cache.lock().unwrap().get_mut("localhost").unwrap().expires = Instant::now();
+        let fresh = resolver.lookup("localhost", 80).await.unwrap();
+        assert!(!fresh.is_empty() && fresh.iter().all(|addr| addr.ip().is_loopback()), "{:?}", fresh);
+        let cache = resolver.cache.lock().unwrap();
+        let entry = &cache["localhost"];
+        assert!(entry.expires > Instant::now() + Duration::from_secs(50));
+        assert!(entry.addrs.iter().all(IpAddr::is_loopback));
+    }
+}
@@ -0,0 +1,268 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use openssl::asn1::Asn1Time;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+
+use crate::config::{Config, DEFAULT_PORT};
+
+// A CA certificate closer than this to expiry is flagged
+const EXPIRY_WARNING_DAYS: i32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+// Outcome of one check and, when something is wrong, what to do about it
+#[derive(Debug)]
+pub struct Finding {
+    pub check: &'static str,
+    pub status: Status,
+    pub message: String,
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            check,
+            status: Status::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(check: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            check,
+            status: Status::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn failed(check: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            check,
+            status: Status::Failed,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+// Load the config file the proxy would use. When it cannot be loaded the
+// defaults are returned, so the remaining checks still run.
+pub fn check_config_file(path: &Path, required: bool) -> (Config, Finding) {
+    if !path.exists() {
+        if required {
+            return (
+                Config::default(),
+                Finding::failed(
+                    "config",
+                    format!("Config file not found: {}", path.display()),
+                    "Check the --config path, or write a commented example with --generate-config",
+                ),
+            );
+        }
+        return (
+            Config::default(),
+            Finding::ok("config", format!("No config file at {}; using the defaults", path.display())),
+        );
+    }
+
+    match Config::load_from_file(path) {
+        Ok(config) => (config, Finding::ok("config", format!("Loaded {}", path.display()))),
+        Err(e) => (
+            Config::default(),
+            Finding::failed(
+                "config",
+                format!("Cannot load {}: {}", path.display(), e),
+                "Correct the setting named above; --generate-config writes an example with every \
+                 option to compare with. The other checks use the defaults.",
+            ),
+        ),
+    }
+}
+
+// Everything that depends on the loaded settings
+pub fn check_settings(config: &Config) -> Vec<Finding> {
+    let mut findings = vec![match config.validate() {
+        Ok(()) => Finding::ok("settings", "Settings are valid"),
+        Err(e) => Finding::failed("settings", e, "Correct this setting in the config file"),
+    }];
+    findings.extend(check_certificate(config));
+    findings.push(check_port(config.proxy.port));
+    if config.tee.enabled {
+        findings.push(check_writable_dir("saved streams", Path::new(&config.tee.dir), "[tee] dir"));
+    }
+    findings
+}
+
+fn check_certificate(config: &Config) -> Vec<Finding> {
+    let cert_file = &config.proxy.cert_file;
+    let key_file = &config.proxy.key_file;
+    let create = format!(
+        "Create a CA with\n  openssl req -x509 -newkey rsa:4096 -keyout {} -out {} -days 365 \
+         -passout pass:\"third-wheel\" -subj \"/CN=mpv-ytproxy\"\n\
+         or point [proxy] cert_file and key_file (-c and -k) at existing files",
+        key_file, cert_file
+    );
+
+    let mut files = Vec::new();
+    for (what, path) in [("certificate", cert_file), ("key", key_file)] {
+        match fs::read(path) {
+            Ok(pem) => files.push(pem),
+            Err(e) => {
+                return vec![Finding::failed(
+                    "certificate",
+                    format!("Cannot read the CA {} {}: {}", what, path, e),
+                    create,
+                )]
+            }
+        }
+    }
+
+    let Ok(cert) = X509::from_pem(&files[0]) else {
+        return vec![Finding::failed(
+            "certificate",
+            format!("{} is not a PEM certificate", cert_file),
+            create,
+        )];
+    };
+    let sources = config.passphrase_sources();
+    let (source, passphrase) = &sources[0];
+    let load = |passphrase: &str| PKey::private_key_from_pem_passphrase(&files[1], passphrase.as_bytes());
+    let key = match load(passphrase) {
+        Ok(key) => key,
+        Err(_) => {
+            let message = format!(
+                "Cannot decrypt the CA key {} with {} (wrong passphrase, or not a PEM private key)",
+                key_file, source
+            );
+            let fix = match sources[1..].iter().find(|(_, other)| load(other).is_ok()) {
+                Some((other, _)) => format!(
+                    "The key opens with {}, which {} overrides; remove {} or set it to the same passphrase",
+                    other, source, source
+                ),
+                None => "Set the passphrase the key was created with in [security] passphrase, the \
+                         YTPROXY_PASSPHRASE environment variable or -s; [security] passphrase takes \
+                         precedence over YTPROXY_PASSPHRASE"
+                    .to_string(),
+            };
+            return vec![Finding::failed("certificate", message, fix)];
+        }
+    };
+
+    if !cert.public_key().is_ok_and(|public| public.public_eq(&key)) {
+        return vec![Finding::failed(
+            "certificate",
+            format!("The key in {} does not belong to the certificate in {}", key_file, cert_file),
+            create,
+        )];
+    }
+
+    vec![
+        Finding::ok("certificate", format!("CA loaded from {} with {}", cert_file, source)),
+        check_expiry(&cert, &create),
+    ]
+}
+
+fn check_expiry(cert: &X509, create: &str) -> Finding {
+    let days_left = Asn1Time::days_from_now(0)
+        .and_then(|now| now.diff(cert.not_after()))
+        .map(|diff| if diff.secs < 0 && diff.days == 0 { -1 } else { diff.days });
+    let not_yet_valid = Asn1Time::days_from_now(0).is_ok_and(|now| cert.not_before() > now);
+    let not_after = cert.not_after();
+
+    match days_left {
+        Err(e) => Finding::warning(
+            "expiry",
+            format!("Cannot read the CA validity period: {}", e),
+            create.to_string(),
+        ),
+        Ok(days) if days < 0 => Finding::failed(
+            "expiry",
+            format!("The CA certificate expired on {}", not_after),
+            create.to_string(),
+        ),
+        Ok(_) if not_yet_valid => Finding::failed(
+            "expiry",
+            format!("The CA certificate is not valid before {}", cert.not_before()),
+            "Check the system clock, or create a new CA",
+        ),
+        Ok(days) if days < EXPIRY_WARNING_DAYS => Finding::warning(
+            "expiry",
+            format!("The CA certificate expires in {} days ({})", days, not_after),
+            create.to_string(),
+        ),
+        Ok(days) => Finding::ok("expiry", format!("The CA certificate is valid until {} ({} days)", not_after, days)),
+    }
+}
+
+fn check_port(port: u16) -> Finding {
+    match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+        Ok(_) if port == 0 => Finding::ok("port", "Port 0: a free port is picked at startup"),
+        Ok(_) => Finding::ok("port", format!("Port {} on 127.0.0.1 is free", port)),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => Finding::failed(
+            "port",
+            format!("Port {} on 127.0.0.1 is in use", port),
+            format!(
+                "Stop the program using it; it is often an http-ytproxy left running by mpv \
+                 (find it with `lsof -i :{}`). Otherwise choose another port with -p or [proxy] port{}",
+                port,
+                // main.lua starts the proxy on the default port
+                if port == DEFAULT_PORT {
+                    "; main.lua expects the proxy on this port"
+                } else {
+                    ""
+                }
+            ),
+        ),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Finding::failed(
+            "port",
+            format!("Not allowed to listen on port {}: {}", port, e),
+            "Ports below 1024 need elevated privileges; use a port above 1024 with -p or [proxy] port",
+        ),
+        Err(e) => Finding::failed(
+            "port",
+            format!("Cannot listen on port {}: {}", port, e),
+            "Choose another port with -p or [proxy] port",
+        ),
+    }
+}
+
+// A directory that is created when missing
+fn check_writable_dir(check: &'static str, dir: &Path, setting: &str) -> Finding {
+    // The closest existing ancestor is where the directory would be created
+    let existing: PathBuf = dir
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf);
+    let message = if existing == dir {
+        format!("{} is writable", dir.display())
+    } else {
+        format!("{} will be created in {}", dir.display(), existing.display())
+    };
+
+    match probe_dir(&existing) {
+        Ok(()) => Finding::ok(check, message),
+        Err(e) => Finding::failed(
+            check,
+            format!("Cannot write to {}: {}", existing.display(), e),
+            format!("Fix the permissions or change {}", setting),
+        ),
+    }
+}
+
+// Create and remove a file in `dir` to see whether it is writable
+fn probe_dir(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".http-ytproxy-doctor-{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
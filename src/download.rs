@@ -0,0 +1,718 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use hyper::http::HeaderValue;
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+#[cfg(feature = "fault-injection")]
+use crate::faults::FaultInjector;
+use crate::har::HarRecorder;
+use crate::hedge::Hedger;
+use crate::inflight::{FetchContext, InFlightChunk, RequestTemplate};
+use crate::pool::BufferPool;
+use crate::proxy::{ResponseFuture, UpstreamClient};
+use crate::ratelimit::{RateLimiter, TokenBucket};
+use crate::replay::Replay;
+use crate::resource::{parse_range, ResourceInfo, ResourceTracker};
+use crate::scheduler::Scheduler;
+use crate::sites::{ChunkPolicy, SitePolicies};
+use crate::stream_key::StreamKeyRules;
+use crate::tee::Tee;
+
+// Chunks and playback position of one stream
+#[derive(Debug, Default)]
+struct StreamState {
+    chunks: Vec<Arc<InFlightChunk>>,
+    // Last client-requested range, used to tell sequential reads from seeks
+    last_range: Option<(u64, u64)>,
+    last_request: Option<Instant>,
+    seeks: u64,
+    // A background download of the whole resource is running
+    background: bool,
+}
+
+// How often a background download checks for idleness and finished chunks
+const BACKGROUND_CHECK: Duration = Duration::from_secs(1);
+
+// Download whole streams in the background once playback starts
+#[derive(Debug, Clone)]
+pub struct BackgroundPolicy {
+    // Shared by all background downloads
+    rate_limit: Option<Arc<TokenBucket>>,
+    idle_timeout: Duration,
+    // Bytes held in memory for one stream
+    max_cached: u64,
+}
+
+impl BackgroundPolicy {
+    // `rate_limit` in bytes per second for all background downloads together
+    pub fn new(rate_limit: Option<u64>, idle_timeout: Duration, max_cached: u64) -> Self {
+        Self {
+            rate_limit: rate_limit.filter(|r| *r > 0).map(|r| Arc::new(TokenBucket::new(r))),
+            idle_timeout,
+            max_cached,
+        }
+    }
+}
+
+// One running background download
+struct BackgroundJob {
+    key: String,
+    policy: BackgroundPolicy,
+    template: RequestTemplate,
+    context: FetchContext,
+    chunk_size: u64,
+    max_concurrent: usize,
+    length: u64,
+}
+
+// What a background download does next
+enum BackgroundStep {
+    Fetch(Arc<InFlightChunk>),
+    // Every byte is held or in flight
+    Done,
+    CacheFull,
+    // The stream is no longer tracked
+    Gone,
+}
+
+// Type alias for complex download tracking
+type DownloadTracker = Arc<Mutex<HashMap<String, StreamState>>>;
+
+// Parallel download manager for intelligent prefetching with memory pooling
+#[derive(Debug)]
+pub struct ParallelDownloadManager {
+    active_downloads: DownloadTracker, // Stream key -> in-flight and recently fetched chunks
+    stream_keys: StreamKeyRules,
+    rate_limiter: RateLimiter,
+    scheduler: Arc<Scheduler>,
+    hedger: Option<Arc<Hedger>>,
+    #[cfg(feature = "fault-injection")]
+    faults: Option<Arc<FaultInjector>>,
+    recorder: Option<Arc<HarRecorder>>,
+    replay: Option<Arc<Replay>>,
+    tee: Option<Arc<Tee>>,
+    background: Option<BackgroundPolicy>,
+    resources: ResourceTracker,
+    max_concurrent: u32,
+    enabled: bool,
+    coalesce: bool,
+    chunk_pool: Arc<BufferPool>,
+    stats_timer: Arc<Mutex<Option<Instant>>>,
+    seek_count: AtomicU64,
+}
+
+impl ParallelDownloadManager {
+    pub fn new(
+        max_concurrent: u32,
+        enabled: bool,
+        memory_pool_enabled: bool,
+        memory_pool_budget: u64,
+        coalesce: bool,
+        stream_keys: StreamKeyRules,
+    ) -> Self {
+        Self {
+            active_downloads: Arc::new(Mutex::new(HashMap::new())),
+            stream_keys,
+            rate_limiter: RateLimiter::default(),
+            scheduler: Scheduler::new(max_concurrent as usize),
+            hedger: None,
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+            recorder: None,
+            replay: None,
+            tee: None,
+            background: None,
+            resources: ResourceTracker::default(),
+            max_concurrent,
+            enabled,
+            coalesce,
+            chunk_pool: BufferPool::new(memory_pool_enabled, memory_pool_budget),
+            stats_timer: Arc::new(Mutex::new(None)),
+            seek_count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    pub fn with_hedger(mut self, hedger: Option<Hedger>) -> Self {
+        self.hedger = hedger.map(Arc::new);
+        self
+    }
+
+    #[cfg(feature = "fault-injection")]
+    pub fn with_faults(mut self, faults: Option<FaultInjector>) -> Self {
+        self.faults = faults.map(Arc::new);
+        self
+    }
+
+    pub fn with_recorder(mut self, recorder: Option<Arc<HarRecorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    // Answer from a recording instead of going upstream
+    pub fn with_replay(mut self, replay: Option<Arc<Replay>>) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    // Save the media of every stream to disk as it is played
+    pub fn with_tee(mut self, tee: Option<Tee>) -> Self {
+        self.tee = tee.map(Arc::new);
+        self
+    }
+
+    pub fn with_background(mut self, background: Option<BackgroundPolicy>) -> Self {
+        self.background = background;
+        self
+    }
+
+    fn new_chunk(&self, start: u64, end: u64, prefetch: bool) -> Arc<InFlightChunk> {
+        // Print stats periodically (every 30 seconds)
+        let mut timer = self.stats_timer.lock().unwrap();
+        let now = Instant::now();
+        if timer.is_none() || timer.unwrap().elapsed().as_secs() >= 30 {
+            self.chunk_pool.print_stats();
+            eprintln!("Seeks detected: {}", self.seek_count.load(Ordering::Relaxed));
+            if let Some(tee) = self.tee.as_ref().filter(|tee| tee.dropped_bytes() > 0) {
+                eprintln!("Bytes not saved while disk writes fell behind: {}", tee.dropped_bytes());
+            }
+            *timer = Some(now);
+        }
+
+        Arc::new(InFlightChunk::new(start, end, prefetch, self.chunk_pool.clone()))
+    }
+
+    // In-flight or cached chunk that already covers `start` and goes on to at
+    // least `end`, if any
+    fn find_chunk(&self, key: &str, start: u64, end: u64) -> Option<Arc<InFlightChunk>> {
+        let active = self.active_downloads.lock().unwrap();
+        active
+            .get(key)?
+            .chunks
+            .iter()
+            .find(|chunk| chunk.covers(start) && chunk.requested_end >= end)
+            .cloned()
+    }
+
+    // Track a new download of [start, end] so later requests can attach to it
+    fn register(&self, key: &str, start: u64, end: u64, prefetch_ahead: u64) -> Arc<InFlightChunk> {
+        let chunk = self.new_chunk(start, end, false);
+        let mut active = self.active_downloads.lock().unwrap();
+        active.entry(key.to_string()).or_default().chunks.push(chunk.clone());
+        self.evict(&mut active, key, start, prefetch_ahead);
+        chunk
+    }
+
+    // Record a client request for [start, end]. A request that starts before the
+    // previous one or beyond the prefetch window after it is a seek: unfinished
+    // prefetches that do not cover the new position are cancelled.
+    fn track_position(&self, key: &str, start: u64, end: u64, prefetch_ahead: u64) {
+        let mut active = self.active_downloads.lock().unwrap();
+        let stream = active.entry(key.to_string()).or_default();
+        let previous = stream.last_range.replace((start, end));
+        stream.last_request = Some(Instant::now());
+
+        let (last_start, last_end) = match previous {
+            Some(range) => range,
+            None => return,
+        };
+        if start >= last_start && start <= last_end.saturating_add(prefetch_ahead).saturating_add(1) {
+            return;
+        }
+
+        stream.seeks += 1;
+        let total = self.seek_count.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut cancelled = 0;
+        stream.chunks.retain(|chunk| {
+            if chunk.is_prefetch() && !chunk.is_kept() && !chunk.covers(start) && chunk.cancel() {
+                cancelled += 1;
+                return false;
+            }
+            true
+        });
+        eprintln!(
+            "Seek detected on {}: {} -> {} (stream seeks: {}, total: {}), cancelled {} prefetches",
+            key, last_start, start, stream.seeks, total, cancelled
+        );
+    }
+
+    // Drop failed chunks, chunks far behind the playback position, and the
+    // oldest finished chunks once more than twice max_concurrent are held.
+    // Chunks kept for a background download only go when they fail.
+    fn evict(&self, active: &mut HashMap<String, StreamState>, key: &str, start: u64, prefetch_ahead: u64) {
+        if let Some(stream) = active.get_mut(key) {
+            stream.chunks.retain(|chunk| {
+                !chunk.is_failed()
+                    && (chunk.is_kept() || start.saturating_sub(chunk.start) < prefetch_ahead.saturating_mul(2))
+            });
+        }
+
+        let limit = (self.max_concurrent as usize * 2).max(2);
+        let held: usize = active
+            .values()
+            .map(|stream| stream.chunks.iter().filter(|chunk| !chunk.is_kept()).count())
+            .sum();
+        if held > limit {
+            let mut finished: Vec<(Instant, String, u64)> = active
+                .iter()
+                .flat_map(|(key, stream)| {
+                    stream
+                        .chunks
+                        .iter()
+                        .filter(|chunk| chunk.is_complete() && !chunk.is_kept())
+                        .map(move |chunk| (chunk.created, key.clone(), chunk.start))
+                })
+                .collect();
+            finished.sort();
+
+            for (_, key, chunk_start) in finished.into_iter().take(held - limit) {
+                if let Some(stream) = active.get_mut(&key) {
+                    stream
+                        .chunks
+                        .retain(|chunk| !(chunk.start == chunk_start && chunk.is_complete() && !chunk.is_kept()));
+                }
+            }
+        }
+
+        // The stream being read keeps its position even with no chunks held
+        active.retain(|stream_key, stream| stream_key == key || !stream.chunks.is_empty());
+    }
+
+    fn should_prefetch(
+        &self,
+        key: &str,
+        start: u64,
+        policy: &ChunkPolicy,
+        resource: &ResourceInfo,
+    ) -> Vec<Arc<InFlightChunk>> {
+        if !self.enabled {
+            return vec![];
+        }
+
+        let mut active = self.active_downloads.lock().unwrap();
+        let downloads = &mut active.entry(key.to_string()).or_default().chunks;
+
+        // Check if we should prefetch next chunks
+        let mut prefetch_chunks = Vec::new();
+        let chunk_size = policy.chunk_size;
+
+        // Calculate how many chunks to prefetch ahead
+        let chunks_to_prefetch = (policy.prefetch_ahead / chunk_size)
+            .min((policy.max_concurrent_chunks as u64).saturating_sub(1));
+
+        for i in 1..=chunks_to_prefetch {
+            // Nothing to prefetch past the largest offset
+            let Some(prefetch_start) = i.checked_mul(chunk_size).and_then(|offset| start.checked_add(offset)) else {
+                break;
+            };
+            let mut prefetch_end = prefetch_start.saturating_add(chunk_size - 1);
+
+            // Nothing to fetch past the end of the resource
+            if let Some(last) = resource.last_byte() {
+                if prefetch_start > last {
+                    break;
+                }
+                prefetch_end = prefetch_end.min(last);
+            }
+
+            // Check if this range is not already being downloaded
+            let already_downloading = downloads.iter().any(|chunk| chunk.covers(prefetch_start));
+
+            if !already_downloading {
+                let chunk = self.new_chunk(prefetch_start, prefetch_end, true);
+                downloads.push(chunk.clone());
+                prefetch_chunks.push(chunk);
+            }
+        }
+
+        self.evict(&mut active, key, start, policy.prefetch_ahead);
+        prefetch_chunks
+    }
+
+    // Start downloading the rest of the stream, unless that is already running
+    fn start_background(
+        self: Arc<Self>,
+        key: &str,
+        background: BackgroundPolicy,
+        template: &RequestTemplate,
+        context: &FetchContext,
+        policy: &ChunkPolicy,
+        length: u64,
+    ) {
+        {
+            let mut active = self.active_downloads.lock().unwrap();
+            let stream = active.entry(key.to_string()).or_default();
+            if stream.background {
+                return;
+            }
+            stream.background = true;
+        }
+
+        let mut context = context.clone();
+        if let Some(cap) = &background.rate_limit {
+            context.limit = context.limit.with_cap(cap.clone());
+        }
+        eprintln!(
+            "Background download of {} started: {} bytes in {} byte chunks",
+            key, length, policy.chunk_size
+        );
+        tokio::spawn(self.background_download(BackgroundJob {
+            key: key.to_string(),
+            policy: background,
+            template: template.clone(),
+            context,
+            chunk_size: policy.chunk_size,
+            max_concurrent: policy.max_concurrent_chunks.max(1) as usize,
+            length,
+        }));
+    }
+
+    // Fetch every missing chunk of the stream, from the playback position to
+    // the end and then from the start, until the stream goes idle. The chunks
+    // are held until then so seeks are served from them.
+    async fn background_download(self: Arc<Self>, job: BackgroundJob) {
+        let BackgroundJob {
+            key,
+            policy: background,
+            template,
+            context,
+            chunk_size,
+            max_concurrent,
+            length,
+        } = job;
+        let mut in_flight: VecDeque<Arc<InFlightChunk>> = VecDeque::new();
+        let mut complete = false;
+        let reason = loop {
+            if self.idle_for(&key) >= background.idle_timeout {
+                break format!("idle for {}s", background.idle_timeout.as_secs());
+            }
+            if let Some(failed) = in_flight.iter().find(|chunk| chunk.is_failed()) {
+                break format!("chunk {}-{} failed", failed.start, failed.requested_end);
+            }
+            in_flight.retain(|chunk| !chunk.is_complete());
+
+            if in_flight.len() < max_concurrent && !complete {
+                match self.next_background_step(&key, chunk_size, length, background.max_cached) {
+                    BackgroundStep::Fetch(chunk) => {
+                        let req = template.with_range(chunk.start, chunk.requested_end);
+                        chunk.spawn_fetch(req, context.clone());
+                        in_flight.push_back(chunk);
+                        continue;
+                    }
+                    BackgroundStep::Done if in_flight.is_empty() => {
+                        eprintln!("Background download of {} complete: {} bytes held", key, length);
+                        complete = true;
+                    }
+                    BackgroundStep::Done => {}
+                    BackgroundStep::CacheFull => {
+                        break format!("{} bytes held, the max_cached limit", background.max_cached);
+                    }
+                    BackgroundStep::Gone => break "stream no longer tracked".to_string(),
+                }
+            }
+
+            match in_flight.front() {
+                Some(chunk) => {
+                    let _ = tokio::time::timeout(BACKGROUND_CHECK, chunk.finished()).await;
+                }
+                None => tokio::time::sleep(BACKGROUND_CHECK).await,
+            }
+        };
+
+        // Cancel what is still downloading and let the held chunks be evicted
+        for chunk in &in_flight {
+            chunk.cancel();
+        }
+        let mut active = self.active_downloads.lock().unwrap();
+        if let Some(stream) = active.get_mut(&key) {
+            stream.background = false;
+            for chunk in &stream.chunks {
+                chunk.set_kept(false);
+            }
+        }
+        eprintln!("Background download of {} stopped: {}", key, reason);
+    }
+
+    // Time since a client last requested part of the stream
+    fn idle_for(&self, key: &str) -> Duration {
+        let active = self.active_downloads.lock().unwrap();
+        active
+            .get(key)
+            .and_then(|stream| stream.last_request)
+            .map_or(Duration::MAX, |last| last.elapsed())
+    }
+
+    // Register the first missing chunk after the playback position, wrapping
+    // around to the start of the resource
+    fn next_background_step(&self, key: &str, chunk_size: u64, length: u64, max_cached: u64) -> BackgroundStep {
+        let mut active = self.active_downloads.lock().unwrap();
+        let Some(stream) = active.get_mut(key) else {
+            return BackgroundStep::Gone;
+        };
+        let held: u64 = stream
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.is_kept())
+            .map(|chunk| chunk.requested_end - chunk.start + 1)
+            .sum();
+
+        let last = length.saturating_sub(1);
+        let position = stream.last_range.map_or(0, |(_, end)| end.saturating_add(1)).min(length);
+        let gap = find_gap(&stream.chunks, position, last)
+            .or_else(|| find_gap(&stream.chunks, 0, position.saturating_sub(1)).filter(|_| position > 0));
+        let Some((start, gap_end)) = gap else {
+            return BackgroundStep::Done;
+        };
+        let end = gap_end.min(start.saturating_add(chunk_size - 1));
+        if held + (end - start + 1) > max_cached {
+            return BackgroundStep::CacheFull;
+        }
+
+        let chunk = self.new_chunk(start, end, true);
+        chunk.set_kept(true);
+        stream.chunks.push(chunk.clone());
+        BackgroundStep::Fetch(chunk)
+    }
+
+    // Serve [start, end] of `req`, attaching to an in-flight download of the
+    // same bytes when there is one, and start any prefetches that are due.
+    // An `exact` range is the client's own and must be served in full; one the
+    // proxy cut may be answered with a shorter part from its start.
+    async fn serve_range(
+        self: Arc<Self>,
+        req: Request<Body>,
+        client: UpstreamClient,
+        (start, end): (u64, u64),
+        exact: bool,
+        policy: Arc<ChunkPolicy>,
+        resource: ResourceInfo,
+    ) -> Result<Response<Body>, hyper::Error> {
+        // Requests for the same media differ in volatile query parameters
+        let key = self.stream_keys.key_for(req.uri());
+        let template = RequestTemplate::from_request(&req);
+        let context = FetchContext {
+            client,
+            limit: self.rate_limiter.for_stream(&key),
+            scheduler: self.scheduler.clone(),
+            hedger: self.hedger.clone(),
+            retry: policy.retry,
+            stream: key.clone(),
+            replay: self.replay.clone(),
+            tee: self.tee.clone(),
+            recorder: self.recorder.clone(),
+            #[cfg(feature = "fault-injection")]
+            faults: self.faults.clone(),
+        };
+
+        self.track_position(&key, start, end, policy.prefetch_ahead);
+        let prefetch_chunks = self.should_prefetch(&key, start, &policy, &resource);
+        if !prefetch_chunks.is_empty() {
+            eprintln!(
+                "Parallel prefetch: {} ranges queued for {}",
+                prefetch_chunks.len(),
+                key
+            );
+            for chunk in prefetch_chunks {
+                let prefetch = template.with_range(chunk.start, chunk.requested_end);
+                chunk.spawn_fetch(prefetch, context.clone());
+            }
+        }
+
+        if let (Some(background), Some(length)) = (&self.background, resource.length) {
+            self.clone()
+                .start_background(&key, background.clone(), &template, &context, &policy, length);
+        }
+
+        if let Some(chunk) = self.find_chunk(&key, start, if exact { end } else { start }) {
+            // A client now waits on it, so it must not queue behind prefetches
+            chunk.promote();
+            let (chunk_start, chunk_end) = (chunk.start, chunk.requested_end);
+            if let Some(response) = chunk.respond(start, end).await {
+                eprintln!(
+                    "Coalesced: bytes={}-{} served from in-flight chunk {}-{}",
+                    start, end, chunk_start, chunk_end
+                );
+                return Ok(response);
+            }
+        }
+
+        let chunk = self.register(&key, start, end, policy.prefetch_ahead);
+        chunk.spawn_fetch(req, context);
+        match chunk.clone().respond(start, end).await {
+            Some(response) => Ok(response),
+            None => Ok(chunk.take_passthrough().unwrap_or_else(|| {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::BAD_GATEWAY;
+                response
+            })),
+        }
+    }
+}
+
+// First run of bytes in [from, to] not covered by any chunk
+fn find_gap(chunks: &[Arc<InFlightChunk>], from: u64, to: u64) -> Option<(u64, u64)> {
+    let mut pos = from;
+    while pos <= to {
+        match chunks.iter().filter(|chunk| chunk.covers(pos)).map(|chunk| chunk.end()).max() {
+            Some(end) => pos = end.saturating_add(1),
+            None => {
+                let next = chunks
+                    .iter()
+                    .filter(|chunk| chunk.start > pos && !chunk.is_failed())
+                    .map(|chunk| chunk.start)
+                    .min()
+                    .unwrap_or(u64::MAX);
+                return Some((pos, to.min(next - 1)));
+            }
+        }
+    }
+    None
+}
+
+pub fn mitm(
+    mut req: Request<Body>,
+    client: UpstreamClient,
+    policies: Arc<SitePolicies>,
+    download_manager: Arc<ParallelDownloadManager>,
+) -> ResponseFuture {
+    let host = req.uri().host().unwrap_or_default().to_string();
+    let policy = policies.for_request(&host, Some(req.uri().path()));
+
+    // Byte range that will be requested upstream, once the header is rewritten
+    let mut requested_range = None;
+    // The client's own range, not cut by the proxy
+    let mut exact_range = false;
+    let key = download_manager.stream_keys.key_for(req.uri());
+    let resource = download_manager.resources.lookup(&key, req.uri());
+    let hdr = req.headers_mut();
+    let original_range = download_manager
+        .recorder
+        .as_ref()
+        .and_then(|_| hdr.get("Range")?.to_str().ok().map(str::to_string));
+
+    // Hosts of disabled or unknown sites, and subtitles, manifests and the
+    // like, are passed through as requested
+    if policy.is_none() && hdr.contains_key("Range") {
+        eprintln!("Range unchanged: {} is not an enabled site", host);
+    } else if !resource.is_media() && hdr.contains_key("Range") {
+        eprintln!(
+            "Range unchanged: {} is not media",
+            resource.mime.as_deref().unwrap_or("unknown type")
+        );
+    }
+
+    // Only process Range headers for optimization
+    let range = hdr.get("Range").filter(|_| resource.is_media());
+    if let (Some(val), Some(policy)) = (range, &policy) {
+        let http_chunk_size = policy.chunk_size;
+        // Safely convert header value to string and clone it to avoid borrow issues
+        if let Ok(range) = val.to_str() {
+            let range_string = range.to_string();
+
+            // Parse Range header: bytes=start-end or bytes=start-
+            if let Some((start, end)) = parse_range(&range_string) {
+                // Open-ended ranges like "bytes=0-" are always cut; closed ones
+                // like "bytes=0-1023" only when larger than a chunk
+                let should_modify = end.is_none_or(|end| (end - start).saturating_add(1) > http_chunk_size);
+
+                // Past the end: let upstream answer 416 for the original range
+                let past_end = resource.last_byte().is_some_and(|last| start > last);
+
+                if should_modify && past_end {
+                    eprintln!(
+                        "Range unchanged: {} starts past the resource end ({} bytes)",
+                        range_string,
+                        resource.length.unwrap_or(0)
+                    );
+                } else if should_modify {
+                    // Calculate new end position
+                    if let Some(new_end) = start.checked_add(http_chunk_size) {
+                        // The last chunk ends with the resource
+                        let new_end_byte = new_end
+                            .saturating_sub(1)
+                            .min(resource.last_byte().unwrap_or(u64::MAX));
+                        let newrange = format!("bytes={}-{}", start, new_end_byte);
+
+                        // Safely create header value
+                        if let Ok(header_val) = HeaderValue::from_str(&newrange) {
+                            hdr.insert("Range", header_val);
+                            eprintln!(
+                                "Range chunked: {} -> {} (chunk size: {})",
+                                range_string, newrange, http_chunk_size
+                            );
+
+                            requested_range = Some((start, new_end_byte));
+                        } else {
+                            eprintln!(
+                                "Warning: Failed to create header value for: {}",
+                                newrange
+                            );
+                        }
+                    } else {
+                        eprintln!(
+                            "Warning: Range overflow detected, skipping modification"
+                        );
+                    }
+                } else {
+                    eprintln!("Range unchanged: {} (already optimal)", range_string);
+                    requested_range = end.map(|end| (start, end));
+                    exact_range = true;
+                }
+            } else {
+                // Multiple ranges, suffix ranges, an end before the start...
+                eprintln!("Range unchanged: {} is not a single byte range", range_string);
+            }
+        } else {
+            eprintln!("Warning: Invalid UTF-8 in Range header, skipping modification");
+        }
+    }
+
+    let exchange = download_manager
+        .recorder
+        .as_ref()
+        .map(|recorder| recorder.begin(&req, original_range));
+
+    Box::pin(async move {
+        let result = match (requested_range, policy) {
+            (Some((start, end)), Some(policy)) if download_manager.coalesce && req.method() == Method::GET => {
+                download_manager
+                    .clone()
+                    .serve_range(req, client, (start, end), exact_range, policy, resource)
+                    .await
+            }
+            _ => {
+                let limit = download_manager.rate_limiter.for_stream(&key);
+                #[cfg(feature = "fault-injection")]
+                let target = req.uri().to_string();
+                let response = match &download_manager.replay {
+                    Some(replay) => replay.respond(req).await,
+                    None => client.request(req).await,
+                };
+                #[cfg(feature = "fault-injection")]
+                let response = match &download_manager.faults {
+                    Some(faults) => faults.inject(&target, response),
+                    None => response,
+                };
+                // Chunks fetched for coalescing are saved as they arrive
+                response.map(|response| match &download_manager.tee {
+                    Some(tee) => tee.observe(&key, limit.throttle(response)),
+                    None => limit.throttle(response),
+                })
+            }
+        };
+        let response = match (&download_manager.recorder, exchange) {
+            (Some(recorder), Some(exchange)) => recorder.record(exchange, result)?,
+            _ => result?,
+        };
+        download_manager
+            .resources
+            .learn(&key, response.status(), response.headers());
+        Ok(response)
+    })
+}
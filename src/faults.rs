@@ -0,0 +1,222 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+// Cut point for bodies without a Content-Length
+const UNKNOWN_LENGTH_CUT: f64 = 256.0 * 1024.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FaultKind {
+    // 429 Too Many Requests with Retry-After
+    #[serde(rename = "429")]
+    TooManyRequests,
+    // 503 Service Unavailable
+    #[serde(rename = "5xx")]
+    ServerError,
+    // Connection reset part-way through the body
+    #[serde(rename = "reset")]
+    Reset,
+    // Body delivered at the configured drip rate
+    #[serde(rename = "drip")]
+    SlowDrip,
+    // Body ends early but cleanly, short of its Content-Length
+    #[serde(rename = "truncate")]
+    Truncate,
+}
+
+impl FaultKind {
+    pub const ALL: [FaultKind; 5] = [
+        FaultKind::TooManyRequests,
+        FaultKind::ServerError,
+        FaultKind::Reset,
+        FaultKind::SlowDrip,
+        FaultKind::Truncate,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            FaultKind::TooManyRequests => "429",
+            FaultKind::ServerError => "5xx",
+            FaultKind::Reset => "reset",
+            FaultKind::SlowDrip => "drip",
+            FaultKind::Truncate => "truncate",
+        }
+    }
+}
+
+// splitmix64: small, seedable and good enough to pick faults
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// When and how upstream responses are broken
+#[derive(Debug, Clone)]
+pub struct FaultSchedule {
+    // Same seed and request order give the same faults; None picks one
+    pub seed: Option<u64>,
+    // Chance that a response gets a fault
+    pub probability: f64,
+    // Also break every Nth response (0 = off)
+    pub every: u64,
+    // Leave this many responses alone first, e.g. to let playback start
+    pub start_after: u64,
+    pub kinds: Vec<FaultKind>,
+    // Bytes per second for "drip"
+    pub drip_rate: u64,
+}
+
+// Injects faults into upstream responses before the proxy sees them, so the
+// retry, hedging and prefetch paths can be exercised locally
+#[derive(Debug)]
+pub struct FaultInjector {
+    schedule: FaultSchedule,
+    seed: u64,
+    responses: AtomicU64,
+    rng: Mutex<Rng>,
+}
+
+impl FaultInjector {
+    pub fn new(schedule: FaultSchedule) -> Self {
+        let seed = schedule.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        });
+        Self {
+            schedule,
+            seed,
+            responses: AtomicU64::new(0),
+            rng: Mutex::new(Rng(seed)),
+        }
+    }
+
+    // Seed in use, to reproduce a run with `seed = ...`
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Fault for the next upstream response and where to cut its body, as a
+    // fraction of its length
+    fn pick(&self) -> Option<(u64, FaultKind, f64)> {
+        let n = self.responses.fetch_add(1, Ordering::Relaxed) + 1;
+        if n <= self.schedule.start_after || self.schedule.kinds.is_empty() {
+            return None;
+        }
+
+        let mut rng = self.rng.lock().unwrap();
+        let roll = rng.next_f64();
+        let scheduled =
+            self.schedule.every > 0 && (n - self.schedule.start_after).is_multiple_of(self.schedule.every);
+        if !scheduled && roll >= self.schedule.probability {
+            return None;
+        }
+        let kind = self.schedule.kinds[(rng.next_u64() % self.schedule.kinds.len() as u64) as usize];
+        let cut = 0.1 + 0.8 * rng.next_f64();
+        Some((n, kind, cut))
+    }
+
+    // Apply the next scheduled fault, if any, to an upstream response
+    pub fn inject(
+        &self,
+        target: &str,
+        result: Result<Response<Body>, hyper::Error>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let response = result?;
+        let Some((n, kind, cut)) = self.pick() else {
+            return Ok(response);
+        };
+
+        let status = match kind {
+            FaultKind::TooManyRequests => Some(StatusCode::TOO_MANY_REQUESTS),
+            FaultKind::ServerError => Some(StatusCode::SERVICE_UNAVAILABLE),
+            _ => None,
+        };
+        if let Some(status) = status {
+            eprintln!("Fault injected: {} on {} (upstream response #{})", kind.name(), target, n);
+            let mut replacement = Response::new(Body::empty());
+            *replacement.status_mut() = status;
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                replacement.headers_mut().insert(RETRY_AFTER, "1".parse().unwrap());
+            }
+            return Ok(replacement);
+        }
+
+        let length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        let cut_at = (length.map_or(UNKNOWN_LENGTH_CUT, |length| length as f64) * cut) as u64;
+        match kind {
+            FaultKind::SlowDrip => eprintln!(
+                "Fault injected: drip at {}B/s on {} (upstream response #{})",
+                self.schedule.drip_rate, target, n
+            ),
+            _ => eprintln!(
+                "Fault injected: {} after {} bytes on {} (upstream response #{})",
+                kind.name(),
+                cut_at,
+                target,
+                n
+            ),
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = rewrite_body(body, kind, cut_at, self.schedule.drip_rate.max(1));
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+// Forward `body` through a channel, dripping it or stopping at `cut_at`
+fn rewrite_body(mut body: Body, kind: FaultKind, cut_at: u64, drip_rate: u64) -> Body {
+    let (mut sender, replacement) = Body::channel();
+    tokio::spawn(async move {
+        let mut sent = 0u64;
+        while let Some(data) = body.data().await {
+            let Ok(mut data) = data else {
+                sender.abort();
+                return;
+            };
+
+            let cut = matches!(kind, FaultKind::Reset | FaultKind::Truncate)
+                && sent + data.len() as u64 >= cut_at;
+            if cut {
+                data = data.slice(..(cut_at - sent) as usize);
+            }
+            if kind == FaultKind::SlowDrip {
+                tokio::time::sleep(Duration::from_secs_f64(data.len() as f64 / drip_rate as f64)).await;
+            }
+            sent += data.len() as u64;
+            if !data.is_empty() && sender.send_data(data).await.is_err() {
+                return;
+            }
+
+            if cut {
+                // Dropping the sender ends the body cleanly; abort makes it an error
+                if kind == FaultKind::Reset {
+                    sender.abort();
+                }
+                return;
+            }
+        }
+    });
+    replacement
+}
@@ -0,0 +1,523 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use base64::Engine;
+use futures_util::stream;
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::http::HeaderMap;
+use hyper::{Body, Request, Response, Uri};
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::inflight::ChunkId;
+
+// Entries finished within this window are written to disk together
+const FLUSH_DELAY: Duration = Duration::from_millis(500);
+
+// Query parameters and headers that carry signatures, tokens or the viewer's
+// address; their values are replaced so a HAR can be shared
+const REDACTED_PARAMS: [&str; 9] = ["sig", "lsig", "signature", "key", "token", "access_token", "auth", "pot", "ip"];
+const REDACTED_HEADERS: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+const REDACTED: &str = "REDACTED";
+
+// Closes the entries array and the log; kept at the end of the file and
+// overwritten by the next entries
+const TRAILER: &[u8] = b"\n]}}\n";
+
+#[derive(Debug, Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: Cache,
+    timings: Timings,
+    // Range as the player sent it and as it went upstream, when rewritten
+    #[serde(rename = "_originalRange", skip_serializing_if = "Option::is_none")]
+    original_range: Option<String>,
+    #[serde(rename = "_rewrittenRange", skip_serializing_if = "Option::is_none")]
+    rewritten_range: Option<String>,
+    // Chunk download the entry belongs to. Entries with an attempt number are
+    // requests the proxy sent upstream for it; the others are player requests
+    // answered from it.
+    #[serde(rename = "_chunk", skip_serializing_if = "Option::is_none")]
+    chunk: Option<u64>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    attempt: Option<Attempt>,
+    // Why the exchange failed or ended early
+    #[serde(rename = "_error", skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: String,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    cookies: Vec<NameValue>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    headers: Vec<NameValue>,
+    cookies: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: u64,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct Cache {}
+
+#[derive(Debug, Serialize)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+// Which upstream request of a chunk download an entry is
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Attempt {
+    #[serde(skip)]
+    pub chunk: u64,
+    // 1 for the first request, 2 for the first retry, ...
+    #[serde(rename = "_attempt")]
+    pub number: u32,
+    // Duplicate sent by request hedging
+    #[serde(rename = "_hedge", skip_serializing_if = "std::ops::Not::not")]
+    pub hedge: bool,
+    // Prefetch or background download no player was waiting for yet
+    #[serde(rename = "_prefetch", skip_serializing_if = "std::ops::Not::not")]
+    pub prefetch: bool,
+}
+
+// A request on its way upstream, captured after the Range rewrite
+#[derive(Debug)]
+pub struct Exchange {
+    request: HarRequest,
+    original_range: Option<String>,
+    rewritten_range: Option<String>,
+    attempt: Option<Attempt>,
+    started_at: SystemTime,
+    started: Instant,
+}
+
+// The HAR file with the entries written so far
+#[derive(Debug)]
+struct HarFile {
+    file: File,
+    written: usize,
+}
+
+// Writes every proxied exchange to a HAR file, so a stalled playback can be
+// traced to the chunk that failed and the Range rewrite that produced it.
+// Besides the player's requests, every request sent upstream for a chunk is
+// an entry of its own: retries, hedged duplicates and prefetches.
+#[derive(Debug)]
+pub struct HarRecorder {
+    path: PathBuf,
+    bodies: bool,
+    // Finished entries not yet written
+    entries: Mutex<Vec<Entry>>,
+    file: Mutex<HarFile>,
+    flush_pending: AtomicBool,
+}
+
+impl HarRecorder {
+    // Fails early if `path` can't be written. Bodies of the player's responses
+    // are recorded with `bodies`; the file grows by their size.
+    pub fn create(path: impl Into<PathBuf>, bodies: bool) -> io::Result<Self> {
+        let path = path.into();
+        let mut file = File::create(&path)?;
+        let creator = Creator {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+        };
+        write!(
+            file,
+            "{{\"log\":{{\"version\":\"1.2\",\"creator\":{},\"entries\":[",
+            serde_json::to_string(&creator)?
+        )?;
+        file.write_all(TRAILER)?;
+
+        Ok(Self {
+            path,
+            bodies,
+            entries: Mutex::new(Vec::new()),
+            file: Mutex::new(HarFile { file, written: 0 }),
+            flush_pending: AtomicBool::new(false),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn records_bodies(&self) -> bool {
+        self.bodies
+    }
+
+    // Snapshot `req` as it will be sent upstream. `original_range` is the
+    // Range header the player sent, before any rewrite.
+    pub(crate) fn begin(&self, req: &Request<Body>, original_range: Option<String>) -> Exchange {
+        let range = req
+            .headers()
+            .get("Range")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let rewritten_range = range.filter(|range| original_range.as_deref() != Some(range.as_str()));
+
+        let query_string = req.uri().query().map(redact_query).unwrap_or_default();
+        let url = redacted_url(req.uri());
+
+        Exchange {
+            attempt: None,
+            request: HarRequest {
+                method: req.method().to_string(),
+                url,
+                http_version: format!("{:?}", req.version()),
+                headers: header_list(req.headers()),
+                query_string,
+                cookies: Vec::new(),
+                headers_size: -1,
+                body_size: 0,
+            },
+            original_range,
+            rewritten_range,
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+        }
+    }
+
+    // Snapshot one upstream request of a chunk download
+    pub(crate) fn begin_attempt(&self, req: &Request<Body>, attempt: Attempt) -> Exchange {
+        let mut exchange = self.begin(req, None);
+        exchange.rewritten_range = None;
+        exchange.attempt = Some(attempt);
+        exchange
+    }
+
+    // Await the response to an upstream request begun with `begin_attempt`
+    // and record it. The entry is written even when this is dropped before an
+    // answer, as happens to the slower of two hedged requests.
+    pub(crate) async fn attempt(
+        self: &Arc<Self>,
+        exchange: Exchange,
+        request: impl Future<Output = Result<Response<Body>, hyper::Error>>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let mut unanswered = PendingEntry::new(self.clone(), exchange);
+        unanswered.error = Some("cancelled before a response".to_string());
+
+        let result = request.await;
+        match unanswered.exchange.take() {
+            Some(exchange) => self.record(exchange, result),
+            None => result,
+        }
+    }
+
+    // Record the outcome of `exchange`. The entry is completed when the
+    // response body ends, fails or is dropped by the player.
+    pub(crate) fn record(
+        self: &Arc<Self>,
+        exchange: Exchange,
+        result: Result<Response<Body>, hyper::Error>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let bodies = self.bodies && exchange.attempt.is_none();
+        let mut pending = PendingEntry::new(self.clone(), exchange);
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                // Dropping the entry records it, with status 0
+                pending.finish(Some(e.to_string()));
+                return Err(e);
+            }
+        };
+
+        let (parts, body) = response.into_parts();
+        pending.status = parts.status.as_u16();
+        pending.status_text = parts.status.canonical_reason().unwrap_or_default().to_string();
+        pending.http_version = format!("{:?}", parts.version);
+        pending.headers = header_list(&parts.headers);
+        pending.mime_type = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        pending.length = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        pending.body = bodies.then(Vec::new);
+        pending.chunk = parts.extensions.get::<ChunkId>().map(|id| id.0);
+
+        let observed = stream::unfold((body, pending), |(mut body, mut pending)| async move {
+            match body.data().await {
+                Some(Ok(data)) => {
+                    pending.received += data.len() as u64;
+                    if let Some(captured) = &mut pending.body {
+                        captured.extend_from_slice(&data);
+                    }
+                    Some((Ok(data), (body, pending)))
+                }
+                Some(Err(e)) => {
+                    pending.error = Some(e.to_string());
+                    Some((Err(e), (body, pending)))
+                }
+                None => {
+                    pending.finish(None);
+                    None
+                }
+            }
+        });
+        Ok(Response::from_parts(parts, Body::wrap_stream(observed)))
+    }
+
+    // Write the entries finished so far
+    pub fn flush(&self) {
+        self.flush_pending.store(false, Ordering::Relaxed);
+        if let Err(e) = self.write() {
+            eprintln!("Warning: Failed to write HAR file '{}': {}", self.path.display(), e);
+        }
+    }
+
+    fn push(self: &Arc<Self>, entry: Entry) {
+        self.entries.lock().unwrap().push(entry);
+        if self.flush_pending.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let recorder = self.clone();
+                runtime.spawn(async move {
+                    tokio::time::sleep(FLUSH_DELAY).await;
+                    let _ = tokio::task::spawn_blocking(move || recorder.flush()).await;
+                });
+            }
+            Err(_) => self.flush(),
+        }
+    }
+
+    // Append the new entries in place of the trailer and close the log again,
+    // so the file is valid JSON between writes
+    fn write(&self) -> io::Result<()> {
+        let mut har = self.file.lock().unwrap();
+        let entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let HarFile { file, written } = &mut *har;
+        file.seek(SeekFrom::End(-(TRAILER.len() as i64)))?;
+        let mut out = BufWriter::new(&*file);
+        for entry in &entries {
+            out.write_all(if *written == 0 { b"\n" } else { b",\n" })?;
+            serde_json::to_writer(&mut out, entry)?;
+            *written += 1;
+        }
+        out.write_all(TRAILER)?;
+        out.flush()
+    }
+}
+
+// A response whose body is still being delivered to the player
+struct PendingEntry {
+    recorder: Arc<HarRecorder>,
+    exchange: Option<Exchange>,
+    status: u16,
+    status_text: String,
+    http_version: String,
+    headers: Vec<NameValue>,
+    mime_type: String,
+    length: Option<u64>,
+    wait: Duration,
+    received: u64,
+    body: Option<Vec<u8>>,
+    // Chunk a player response was served from
+    chunk: Option<u64>,
+    error: Option<String>,
+    complete: bool,
+}
+
+impl PendingEntry {
+    fn new(recorder: Arc<HarRecorder>, exchange: Exchange) -> Self {
+        Self {
+            wait: exchange.started.elapsed(),
+            recorder,
+            exchange: Some(exchange),
+            status: 0,
+            status_text: String::new(),
+            http_version: String::new(),
+            headers: Vec::new(),
+            mime_type: String::new(),
+            length: None,
+            received: 0,
+            body: None,
+            chunk: None,
+            error: None,
+            complete: false,
+        }
+    }
+
+    // The body ended, or there was never one
+    fn finish(&mut self, error: Option<String>) {
+        self.complete = true;
+        self.error = error.or(self.error.take());
+    }
+
+    fn take_entry(&mut self) -> Option<Entry> {
+        let exchange = self.exchange.take()?;
+        let total = exchange.started.elapsed();
+        // hyper stops reading a body once Content-Length bytes have been sent
+        let error = self.error.take().or_else(|| match self.length {
+            Some(length) if self.received >= length => None,
+            Some(length) if self.complete => {
+                Some(format!("body ended after {} of {} bytes", self.received, length))
+            }
+            _ if !self.complete && exchange.attempt.is_some() => {
+                Some(format!("chunk download stopped after {} bytes", self.received))
+            }
+            _ if !self.complete => Some(format!(
+                "player closed the connection after {} bytes",
+                self.received
+            )),
+            _ => None,
+        });
+        let text = self
+            .body
+            .take()
+            .map(|body| base64::engine::general_purpose::STANDARD.encode(body));
+
+        Some(Entry {
+            started_date_time: OffsetDateTime::from(exchange.started_at)
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            time: millis(total),
+            request: exchange.request,
+            response: HarResponse {
+                status: self.status,
+                status_text: std::mem::take(&mut self.status_text),
+                http_version: std::mem::take(&mut self.http_version),
+                headers: std::mem::take(&mut self.headers),
+                cookies: Vec::new(),
+                content: Content {
+                    size: self.received,
+                    mime_type: std::mem::take(&mut self.mime_type),
+                    encoding: text.as_ref().map(|_| "base64"),
+                    text,
+                },
+                redirect_url: String::new(),
+                headers_size: -1,
+                body_size: self.received as i64,
+            },
+            cache: Cache {},
+            timings: Timings {
+                send: 0.0,
+                wait: millis(self.wait),
+                receive: millis(total.saturating_sub(self.wait)),
+            },
+            original_range: exchange.original_range,
+            rewritten_range: exchange.rewritten_range,
+            chunk: exchange.attempt.map(|attempt| attempt.chunk).or(self.chunk),
+            attempt: exchange.attempt,
+            error,
+        })
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        if let Some(entry) = self.take_entry() {
+            let recorder = self.recorder.clone();
+            recorder.push(entry);
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// `uri` with secret query parameter values replaced
+pub(crate) fn redacted_url(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.to_string();
+    };
+    let full = uri.to_string();
+    let base = &full[..full.len() - query.len() - 1];
+    let query: Vec<String> = redact_query(query)
+        .iter()
+        .map(|param| format!("{}={}", param.name, param.value))
+        .collect();
+    format!("{}?{}", base, query.join("&"))
+}
+
+fn redact_query(query: &str) -> Vec<NameValue> {
+    query.split('&').filter(|pair| !pair.is_empty()).map(redact_param).collect()
+}
+
+fn redact_param(pair: &str) -> NameValue {
+    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+    let secret = REDACTED_PARAMS.contains(&name.to_ascii_lowercase().as_str());
+    NameValue {
+        name: name.to_string(),
+        value: if secret { REDACTED.to_string() } else { value.to_string() },
+    }
+}
+
+fn header_list(headers: &HeaderMap) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| NameValue {
+            name: name.to_string(),
+            value: if REDACTED_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            },
+        })
+        .collect()
+}
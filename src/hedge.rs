@@ -0,0 +1,291 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use hyper::header::HOST;
+use hyper::http::uri::Authority;
+use hyper::{Body, Request, Response, Uri};
+
+use crate::inflight::clone_request;
+use crate::scheduler::Scheduler;
+
+// Time-to-first-byte samples kept for the percentile
+const MAX_SAMPLES: usize = 200;
+// Below this many samples the configured default delay is used
+const MIN_SAMPLES: usize = 10;
+
+// Sends a duplicate of a chunk request when the first one is slower to answer
+// than most recent requests, and keeps whichever response arrives first
+#[derive(Debug)]
+pub struct Hedger {
+    percentile: f64,
+    min_delay: Duration,
+    default_delay: Duration,
+    // Try another googlevideo node from the URL's `mn` list for the duplicate
+    alternate_nodes: bool,
+    // Static host -> alternate host for the duplicate
+    alternate_hosts: HashMap<String, String>,
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl Hedger {
+    pub fn new(
+        percentile: f64,
+        min_delay: Duration,
+        default_delay: Duration,
+        alternate_nodes: bool,
+        alternate_hosts: HashMap<String, String>,
+    ) -> Self {
+        Self {
+            percentile: percentile.clamp(1.0, 100.0),
+            min_delay,
+            default_delay,
+            alternate_nodes,
+            alternate_hosts: alternate_hosts
+                .into_iter()
+                .map(|(host, alternate)| (host.to_lowercase(), alternate))
+                .collect(),
+            samples: Mutex::new(VecDeque::with_capacity(MAX_SAMPLES)),
+        }
+    }
+
+    // How long to wait for response headers before hedging
+    fn threshold(&self) -> Duration {
+        let samples = self.samples.lock().unwrap();
+        if samples.len() < MIN_SAMPLES {
+            return self.default_delay.max(self.min_delay);
+        }
+
+        let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+        sorted.sort();
+        let rank = ((self.percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1].max(self.min_delay)
+    }
+
+    fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    // Send `req` with `send`, hedging with a duplicate once the latency
+    // threshold passes; `send` is told which one is the duplicate. The
+    // duplicate needs a free download slot; without one the original is
+    // simply awaited.
+    pub async fn request<F, R>(
+        &self,
+        send: F,
+        req: Request<Body>,
+        scheduler: &Arc<Scheduler>,
+        stream: &str,
+        foreground: bool,
+    ) -> Result<Response<Body>, hyper::Error>
+    where
+        F: Fn(Request<Body>, bool) -> R,
+        R: Future<Output = Result<Response<Body>, hyper::Error>>,
+    {
+        let duplicate = self.duplicate(&req);
+        let origin = authority_of(req.uri()).to_string();
+        let started = Instant::now();
+        let primary = send(req, false);
+        tokio::pin!(primary);
+
+        let delay = self.threshold();
+        let duplicate = tokio::select! {
+            result = &mut primary => {
+                if result.is_ok() {
+                    self.record(started.elapsed());
+                }
+                return result;
+            }
+            _ = tokio::time::sleep(delay) => duplicate,
+        };
+
+        let _slot = match scheduler.try_acquire(stream, foreground) {
+            Some(slot) => slot,
+            None => {
+                let result = primary.await;
+                if result.is_ok() {
+                    self.record(started.elapsed());
+                }
+                return result;
+            }
+        };
+
+        eprintln!(
+            "Hedging: no response from {} after {}ms, duplicating to {}",
+            origin,
+            delay.as_millis(),
+            authority_of(duplicate.uri())
+        );
+        let hedge_started = Instant::now();
+        let hedge = send(duplicate, true);
+        tokio::pin!(hedge);
+
+        // First successful response wins; an error only counts once both failed
+        tokio::select! {
+            result = &mut primary => match result {
+                Ok(response) => {
+                    self.record(started.elapsed());
+                    Ok(response)
+                }
+                Err(_) => hedge.await.inspect(|_| self.record(hedge_started.elapsed())),
+            },
+            result = &mut hedge => match result {
+                Ok(response) => {
+                    eprintln!("Hedging: duplicate answered first");
+                    self.record(hedge_started.elapsed());
+                    Ok(response)
+                }
+                Err(_) => primary.await.inspect(|_| self.record(started.elapsed())),
+            },
+        }
+    }
+
+    fn duplicate(&self, req: &Request<Body>) -> Request<Body> {
+        let mut duplicate = clone_request(req);
+
+        if let Some(host) = self.alternate_host(req.uri()) {
+            if let Some(uri) = with_host(req.uri(), &host) {
+                if let Ok(value) = authority_of(&uri).parse() {
+                    duplicate.headers_mut().insert(HOST, value);
+                }
+                *duplicate.uri_mut() = uri;
+            }
+        }
+        duplicate
+    }
+
+    fn alternate_host(&self, uri: &Uri) -> Option<String> {
+        let host = uri.host()?.to_lowercase();
+        if let Some(alternate) = self.alternate_hosts.get(&host) {
+            return Some(alternate.clone());
+        }
+        if self.alternate_nodes && host.ends_with(".googlevideo.com") {
+            return alternate_node(&host, uri.query()?);
+        }
+        None
+    }
+}
+
+// "rr3---sn-abc.googlevideo.com" with mn=sn-abc,sn-def -> "rr3---sn-def.googlevideo.com"
+fn alternate_node(host: &str, query: &str) -> Option<String> {
+    let (label, domain) = host.split_once('.')?;
+    let (prefix, node) = label.split_once("---")?;
+    let nodes = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("mn="))?
+        .replace("%2C", ",")
+        .replace("%2c", ",");
+    let alternate = nodes.split(',').find(|candidate| !candidate.is_empty() && *candidate != node)?;
+    Some(format!("{}---{}.{}", prefix, alternate, domain))
+}
+
+fn with_host(uri: &Uri, host: &str) -> Option<Uri> {
+    let authority = match uri.port_u16() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.authority = Some(authority.parse::<Authority>().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+fn authority_of(uri: &Uri) -> &str {
+    uri.authority().map_or("?", |authority| authority.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_hedger(alternate_nodes: bool, alternate_hosts: &[(&str, &str)]) -> Hedger {
+        let alternate_hosts = alternate_hosts
+            .iter()
+            .map(|(host, alternate)| (host.to_string(), alternate.to_string()))
+            .collect();
+        Hedger::new(
+            90.0,
+            Duration::from_millis(50),
+            Duration::from_millis(1000),
+            alternate_nodes,
+            alternate_hosts,
+        )
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).header(HOST, "original").body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn threshold_uses_the_default_until_enough_samples() {
+        let hedger = new_hedger(false, &[]);
+        for _ in 1..MIN_SAMPLES {
+            hedger.record(Duration::from_millis(10));
+        }
+        assert_eq!(hedger.threshold(), Duration::from_millis(1000));
+
+        // Ten samples of 10ms are all below the minimum delay
+        hedger.record(Duration::from_millis(10));
+        assert_eq!(hedger.threshold(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn threshold_follows_the_percentile_of_recent_samples() {
+        let hedger = new_hedger(false, &[]);
+        for ms in 1..=100 {
+            hedger.record(Duration::from_millis(ms * 10));
+        }
+        assert_eq!(hedger.threshold(), Duration::from_millis(900));
+
+        // A full window forgets the oldest samples
+        for _ in 0..MAX_SAMPLES {
+            hedger.record(Duration::from_millis(100));
+        }
+        assert_eq!(hedger.samples.lock().unwrap().len(), MAX_SAMPLES);
+        assert_eq!(hedger.threshold(), Duration::from_millis(100));
+        hedger.record(Duration::from_millis(5000));
+        assert_eq!(hedger.threshold(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn alternate_node_comes_from_the_mn_list() {
+        let query = "itag=22&mn=sn-abc%2Csn-def&mm=31";
+        assert_eq!(
+            alternate_node("rr3---sn-abc.googlevideo.com", query).as_deref(),
+            Some("rr3---sn-def.googlevideo.com")
+        );
+        assert_eq!(
+            alternate_node("rr3---sn-def.googlevideo.com", "mn=sn-abc,sn-def").as_deref(),
+            Some("rr3---sn-abc.googlevideo.com")
+        );
+        // Nothing to switch to
+        assert_eq!(alternate_node("rr3---sn-abc.googlevideo.com", "mn=sn-abc"), None);
+        assert_eq!(alternate_node("rr3---sn-abc.googlevideo.com", "itag=22"), None);
+        assert_eq!(alternate_node("redirector.googlevideo.com", query), None);
+    }
+
+    #[test]
+    fn duplicate_goes_to_the_alternate_host_only() {
+        let hedger = new_hedger(true, &[("Cdn.Example", "cdn2.example")]);
+
+        let duplicate = hedger.duplicate(&get("https://rr3---sn-abc.googlevideo.com/videoplayback?mn=sn-abc,sn-def"));
+        assert_eq!(duplicate.uri().host(), Some("rr3---sn-def.googlevideo.com"));
+        assert_eq!(duplicate.uri().query(), Some("mn=sn-abc,sn-def"));
+        assert_eq!(duplicate.headers()[HOST], "rr3---sn-def.googlevideo.com");
+
+        let duplicate = hedger.duplicate(&get("https://cdn.example:8443/a.mp4"));
+        assert_eq!(duplicate.uri().to_string(), "https://cdn2.example:8443/a.mp4");
+        assert_eq!(duplicate.headers()[HOST], "cdn2.example:8443");
+
+        // Other hosts, and googlevideo without alternate_nodes, are left alone
+        let uri = "https://video.test/a.mp4?mn=sn-abc,sn-def";
+        let duplicate = hedger.duplicate(&get(uri));
+        assert_eq!(duplicate.uri(), uri);
+        assert_eq!(duplicate.headers()[HOST], "original");
+        let uri = "https://rr3---sn-abc.googlevideo.com/videoplayback?mn=sn-abc,sn-def";
+        assert_eq!(new_hedger(false, &[]).duplicate(&get(uri)).uri(), uri);
+    }
+}
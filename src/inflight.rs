@@ -0,0 +1,617 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use bytes::Bytes;
+use futures_util::stream;
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, CONTENT_LENGTH, CONTENT_RANGE, RETRY_AFTER, TRANSFER_ENCODING,
+};
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::sync::{watch, Notify};
+use tokio::task::AbortHandle;
+
+#[cfg(feature = "fault-injection")]
+use crate::faults::FaultInjector;
+use crate::har::{Attempt, HarRecorder};
+use crate::hedge::Hedger;
+use crate::pool::{self, BufferPool, PooledBuffer};
+use crate::proxy::UpstreamClient;
+use crate::ratelimit::StreamLimit;
+use crate::replay::Replay;
+use crate::resource::parse_content_range;
+use crate::scheduler::{Scheduler, Slot};
+use crate::tee::Tee;
+
+// Largest slice handed to a reader at once, so late joiners on a finished
+// chunk still receive the body as a stream rather than one huge frame
+const MAX_READ_SLICE: usize = 256 * 1024;
+
+// How long a chunk waits for memory once the pool budget is used up
+const BUFFER_WAIT: Duration = Duration::from_secs(2);
+
+// Longest Retry-After a chunk request waits out; a longer one is passed on
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+static NEXT_CHUNK_ID: AtomicU64 = AtomicU64::new(1);
+
+// Response extension naming the chunk a response was served from, so the
+// recorder can link it to the chunk's upstream requests
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkId(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Progress {
+    // Waiting for upstream response headers
+    Pending,
+    // Bytes received so far
+    Streaming(usize),
+    Complete(usize),
+    Failed,
+}
+
+#[derive(Debug)]
+struct ChunkState {
+    status: StatusCode,
+    headers: HeaderMap,
+    // Inclusive end actually served by upstream, known once headers arrive
+    end: u64,
+    total: Option<u64>,
+    // Empty until the fetch gets memory from the pool
+    data: PooledBuffer,
+    // Upstream response that could not be shared (not a matching 206);
+    // handed to the client that started the fetch
+    passthrough: Option<Response<Body>>,
+}
+
+// How often a chunk request is repeated after a connection error, a 5xx or a
+// 429, waiting `backoff` before the first retry and doubling it after each.
+// A longer Retry-After from the server is honoured.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+// Everything a chunk download needs besides the request itself
+#[derive(Debug, Clone)]
+pub struct FetchContext {
+    pub client: UpstreamClient,
+    pub limit: StreamLimit,
+    pub scheduler: Arc<Scheduler>,
+    pub hedger: Option<Arc<Hedger>>,
+    pub retry: RetryPolicy,
+    // Stream key, for per-stream scheduling
+    pub stream: String,
+    // Recording that answers instead of upstream
+    pub replay: Option<Arc<Replay>>,
+    // Saves the chunk to disk as it arrives
+    pub tee: Option<Arc<Tee>>,
+    // Records every upstream request
+    pub recorder: Option<Arc<HarRecorder>>,
+    #[cfg(feature = "fault-injection")]
+    pub faults: Option<Arc<FaultInjector>>,
+}
+
+// One upstream range download that any number of client requests can read
+// from while it is still arriving
+#[derive(Debug)]
+pub struct InFlightChunk {
+    pub id: u64,
+    pub start: u64,
+    pub requested_end: u64,
+    pub created: Instant,
+    // Started ahead of playback and not yet read by any client
+    prefetch: AtomicBool,
+    // Held for a background download: not evicted or cancelled by seeks
+    kept: AtomicBool,
+    promoted: Notify,
+    state: Mutex<ChunkState>,
+    task: Mutex<Option<AbortHandle>>,
+    progress: watch::Sender<Progress>,
+    pool: Arc<BufferPool>,
+}
+
+impl InFlightChunk {
+    pub fn new(start: u64, end: u64, prefetch: bool, pool: Arc<BufferPool>) -> Self {
+        let (progress, _) = watch::channel(Progress::Pending);
+        Self {
+            id: NEXT_CHUNK_ID.fetch_add(1, Ordering::Relaxed),
+            start,
+            requested_end: end,
+            created: Instant::now(),
+            prefetch: AtomicBool::new(prefetch),
+            kept: AtomicBool::new(false),
+            promoted: Notify::new(),
+            state: Mutex::new(ChunkState {
+                status: StatusCode::PARTIAL_CONTENT,
+                headers: HeaderMap::new(),
+                end,
+                total: None,
+                data: PooledBuffer::default(),
+                passthrough: None,
+            }),
+            task: Mutex::new(None),
+            progress,
+            pool,
+        }
+    }
+
+    // Whether a request starting at `offset` can be served from this chunk
+    pub fn covers(&self, offset: u64) -> bool {
+        !self.is_failed() && offset >= self.start && offset <= self.end()
+    }
+
+    // Last byte upstream serves, or the requested one until its headers arrive
+    pub fn end(&self) -> u64 {
+        match *self.progress.borrow() {
+            Progress::Pending => self.requested_end,
+            _ => self.state.lock().unwrap().end,
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        *self.progress.borrow() == Progress::Failed
+    }
+
+    pub fn is_complete(&self) -> bool {
+        matches!(*self.progress.borrow(), Progress::Complete(_))
+    }
+
+    // Completes once the download has finished or failed
+    pub async fn finished(&self) {
+        let mut progress = self.progress.subscribe();
+        while matches!(*progress.borrow_and_update(), Progress::Pending | Progress::Streaming(_)) {
+            if progress.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn is_kept(&self) -> bool {
+        self.kept.load(Ordering::Acquire)
+    }
+
+    pub fn set_kept(&self, kept: bool) {
+        self.kept.store(kept, Ordering::Release);
+    }
+
+    pub fn is_prefetch(&self) -> bool {
+        self.prefetch.load(Ordering::Acquire)
+    }
+
+    // A client is reading this chunk: its download stops yielding to others
+    pub fn promote(&self) {
+        if self.prefetch.swap(false, Ordering::AcqRel) {
+            self.promoted.notify_one();
+        }
+    }
+
+    // Run `fetch` in the background, keeping a handle so it can be cancelled
+    pub fn spawn_fetch(self: &Arc<Self>, req: Request<Body>, context: FetchContext) {
+        let handle = tokio::spawn(self.clone().fetch(req, context));
+        *self.task.lock().unwrap() = Some(handle.abort_handle());
+    }
+
+    // Abort an unfinished download; readers see it fail. Returns whether
+    // anything was cancelled.
+    pub fn cancel(&self) -> bool {
+        if self.is_complete() || self.is_failed() {
+            return false;
+        }
+        self.set_progress(Progress::Failed);
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        true
+    }
+
+    // Failed is final, so a fetch racing with `cancel` cannot revive the chunk
+    fn set_progress(&self, next: Progress) {
+        self.progress.send_if_modified(|current| {
+            if *current == Progress::Failed {
+                return false;
+            }
+            *current = next;
+            true
+        });
+    }
+
+    // Foreground slot for chunks a client waits on; prefetches queue for one
+    // unless a client starts waiting meanwhile
+    async fn admit(&self, scheduler: &Arc<Scheduler>, stream: &str) -> Slot {
+        if !self.is_prefetch() {
+            return scheduler.foreground();
+        }
+        tokio::select! {
+            slot = scheduler.prefetch(stream) => slot,
+            _ = self.promoted.notified() => scheduler.foreground(),
+        }
+    }
+
+    // Prefetches pause while a foreground fetch runs, until a client reads them
+    async fn yield_to_foreground(&self, slot: &mut Slot, scheduler: &Scheduler) {
+        if !slot.is_prefetch() {
+            return;
+        }
+        if self.is_prefetch() {
+            tokio::select! {
+                _ = scheduler.foreground_idle() => {}
+                _ = self.promoted.notified() => {}
+            }
+        }
+        if !self.is_prefetch() {
+            slot.promote();
+        }
+    }
+
+    // Send `req` upstream and stream the response body into this chunk
+    async fn fetch(self: Arc<Self>, req: Request<Body>, context: FetchContext) {
+        let FetchContext {
+            client,
+            limit,
+            scheduler,
+            hedger,
+            retry,
+            stream,
+            replay,
+            tee,
+            recorder,
+            #[cfg(feature = "fault-injection")]
+            faults,
+        } = context;
+        // Memory comes before a scheduler slot, so a chunk waiting for the pool
+        // doesn't keep others from running
+        let size = self
+            .requested_end
+            .checked_sub(self.start)
+            .and_then(|last| last.checked_add(1))
+            .filter(|size| *size <= pool::MAX_BUFFER_SIZE)
+            .and_then(|size| usize::try_from(size).ok());
+        let Some(size) = size else {
+            eprintln!(
+                "Chunk {}-{}: not a valid chunk of at most {} bytes",
+                self.start,
+                self.requested_end,
+                pool::MAX_BUFFER_SIZE
+            );
+            self.fail(None);
+            return;
+        };
+        let buffer = match self.pool.acquire(size, BUFFER_WAIT).await {
+            Some(buffer) => buffer,
+            // Prefetches are fetched again when playback gets there; a client
+            // is waiting on anything else
+            None if self.is_prefetch() => {
+                eprintln!(
+                    "Chunk {}-{}: no memory within the pool budget after {}s",
+                    self.start,
+                    self.requested_end,
+                    BUFFER_WAIT.as_secs()
+                );
+                self.fail(None);
+                return;
+            }
+            None => match self.pool.acquire_overflow(size) {
+                Some(buffer) => {
+                    eprintln!(
+                        "Chunk {}-{}: memory pool budget exhausted, allocating {} bytes beyond it",
+                        self.start, self.requested_end, size
+                    );
+                    buffer
+                }
+                None => {
+                    eprintln!(
+                        "Chunk {}-{}: memory pool budget and its overflow allowance exhausted",
+                        self.start, self.requested_end
+                    );
+                    self.fail(None);
+                    return;
+                }
+            },
+        };
+        self.state.lock().unwrap().data = buffer;
+        let mut slot = self.admit(&scheduler, &stream).await;
+
+        // One upstream request, answered by the recording when replaying
+        let send = |req: Request<Body>, number: u32, hedge: bool| {
+            let client = client.clone();
+            let replay = replay.clone();
+            let recorder = recorder.clone();
+            #[cfg(feature = "fault-injection")]
+            let faults = faults.clone();
+            let attempt = Attempt {
+                chunk: self.id,
+                number,
+                hedge,
+                prefetch: self.is_prefetch(),
+            };
+            async move {
+                let exchange = recorder.as_ref().map(|recorder| recorder.begin_attempt(&req, attempt));
+                #[cfg(feature = "fault-injection")]
+                let target = req.uri().to_string();
+                let response = async {
+                    let result = match &replay {
+                        Some(replay) => replay.respond(req).await,
+                        None => client.request(req).await,
+                    };
+                    #[cfg(feature = "fault-injection")]
+                    let result = match &faults {
+                        Some(faults) => faults.inject(&target, result),
+                        None => result,
+                    };
+                    result
+                };
+                match (&recorder, exchange) {
+                    (Some(recorder), Some(exchange)) => recorder.attempt(exchange, response).await,
+                    _ => response.await,
+                }
+            }
+        };
+
+        let mut attempt = 0;
+        let response = loop {
+            let attempt_req = clone_request(&req);
+            let result = match &hedger {
+                Some(hedger) if replay.is_none() => {
+                    let foreground = !slot.is_prefetch();
+                    let send = |req, hedge| send(req, attempt + 1, hedge);
+                    hedger.request(send, attempt_req, &scheduler, &stream, foreground).await
+                }
+                _ => send(attempt_req, attempt + 1, false).await,
+            };
+
+            let retryable = match &result {
+                Ok(response) => {
+                    response.status().is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(_) => true,
+            };
+            if !retryable || attempt >= retry.attempts {
+                break result;
+            }
+
+            let mut backoff = retry.backoff * 2u32.saturating_pow(attempt);
+            if let Some(wait) = result.as_ref().ok().and_then(|response| retry_after(response.headers())) {
+                if wait > MAX_RETRY_AFTER {
+                    break result;
+                }
+                backoff = backoff.max(wait);
+            }
+            attempt += 1;
+            let reason = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            eprintln!(
+                "Chunk {}-{}: {}, retry {}/{} in {}ms",
+                self.start,
+                self.requested_end,
+                reason,
+                attempt,
+                retry.attempts,
+                backoff.as_millis()
+            );
+            tokio::time::sleep(backoff).await;
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Chunk {}-{} failed: {}", self.start, self.requested_end, e);
+                self.fail(None);
+                return;
+            }
+        };
+
+        let served = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_content_range)
+            .filter(|(start, _, _)| response.status() == StatusCode::PARTIAL_CONTENT && *start == self.start);
+        let (end, total) = match served {
+            Some((_, end, total)) => (end.min(self.requested_end), total),
+            None => {
+                self.fail(Some(response));
+                return;
+            }
+        };
+
+        let (parts, mut body) = response.into_parts();
+        let mut copy = tee.and_then(|tee| tee.stream(&stream, parts.status, &parts.headers));
+        {
+            let mut state = self.state.lock().unwrap();
+            state.status = parts.status;
+            state.headers = parts.headers;
+            state.end = end;
+            state.total = total;
+            let needed = (end - self.start + 1) as usize;
+            if state.data.len() < needed {
+                state.data.resize(needed, 0);
+            }
+        }
+        self.set_progress(Progress::Streaming(0));
+
+        let expected = (end - self.start + 1) as usize;
+        let mut received = 0;
+        while let Some(data) = body.data().await {
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    eprintln!("Chunk {}-{} aborted after {} bytes: {}", self.start, end, received, e);
+                    self.fail(None);
+                    return;
+                }
+            };
+
+            let take = data.len().min(expected - received);
+            {
+                let mut state = self.state.lock().unwrap();
+                state.data[received..received + take].copy_from_slice(&data[..take]);
+            }
+            if let Some(copy) = &mut copy {
+                copy.send(&data.slice(..take));
+            }
+            received += take;
+            if self.is_failed() {
+                return;
+            }
+            self.set_progress(Progress::Streaming(received));
+            limit.consume(take).await;
+            if received == expected {
+                break;
+            }
+            self.yield_to_foreground(&mut slot, &scheduler).await;
+        }
+
+        if received < expected {
+            eprintln!("Chunk {}-{} truncated at {} bytes", self.start, end, received);
+            self.fail(None);
+        } else {
+            self.set_progress(Progress::Complete(received));
+        }
+    }
+
+    fn fail(&self, mut passthrough: Option<Response<Body>>) {
+        if let Some(response) = &mut passthrough {
+            response.extensions_mut().insert(ChunkId(self.id));
+        }
+        self.state.lock().unwrap().passthrough = passthrough;
+        self.set_progress(Progress::Failed);
+    }
+
+    // Upstream response that could not be shared, for the request that started the fetch
+    pub fn take_passthrough(&self) -> Option<Response<Body>> {
+        self.state.lock().unwrap().passthrough.take()
+    }
+
+    // Build a 206 response for [start, end] backed by this chunk. Waits for the
+    // upstream headers; returns None when the chunk cannot serve the range.
+    pub async fn respond(self: Arc<Self>, start: u64, end: u64) -> Option<Response<Body>> {
+        let mut progress = self.progress.subscribe();
+        while *progress.borrow_and_update() == Progress::Pending {
+            if progress.changed().await.is_err() {
+                return None;
+            }
+        }
+        if self.is_failed() {
+            return None;
+        }
+
+        let mut response = Response::new(Body::empty());
+        response.extensions_mut().insert(ChunkId(self.id));
+        let last = {
+            let state = self.state.lock().unwrap();
+            let last = end.min(state.end);
+            if start > last {
+                return None;
+            }
+
+            *response.status_mut() = state.status;
+            let headers = response.headers_mut();
+            for (name, value) in &state.headers {
+                if name != CONTENT_LENGTH && name != CONTENT_RANGE && name != TRANSFER_ENCODING && name != CONNECTION {
+                    headers.append(name, value.clone());
+                }
+            }
+            let total = state.total.map_or_else(|| "*".to_string(), |t| t.to_string());
+            let content_range = format!("bytes {}-{}/{}", start, last, total);
+            headers.insert(CONTENT_RANGE, HeaderValue::from_str(&content_range).ok()?);
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(last - start + 1));
+            last
+        };
+
+        let body = stream::unfold(
+            (self, progress, start, last + 1),
+            |(chunk, mut progress, pos, stop)| async move {
+                if pos >= stop {
+                    return None;
+                }
+                loop {
+                    let current = *progress.borrow_and_update();
+                    let available = match current {
+                        Progress::Streaming(n) | Progress::Complete(n) => chunk.start + n as u64,
+                        Progress::Failed => {
+                            let error = io::Error::other("upstream chunk download failed");
+                            return Some((Err(error), (chunk, progress, stop, stop)));
+                        }
+                        Progress::Pending => chunk.start,
+                    };
+
+                    if available > pos {
+                        let until = available.min(stop).min(pos + MAX_READ_SLICE as u64);
+                        let bytes = {
+                            let state = chunk.state.lock().unwrap();
+                            let from = (pos - chunk.start) as usize;
+                            let to = (until - chunk.start) as usize;
+                            Bytes::copy_from_slice(&state.data[from..to])
+                        };
+                        return Some((Ok(bytes), (chunk, progress, until, stop)));
+                    }
+
+                    if progress.changed().await.is_err() {
+                        let error = io::Error::other("upstream chunk download abandoned");
+                        return Some((Err(error), (chunk, progress, stop, stop)));
+                    }
+                }
+            },
+        );
+        *response.body_mut() = Body::wrap_stream(body);
+        Some(response)
+    }
+}
+
+// Delay asked for by a Retry-After header, in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+// Copy of a bodiless request, for sending it again
+pub fn clone_request(req: &Request<Body>) -> Request<Body> {
+    let mut copy = Request::new(Body::empty());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    copy
+}
+
+// Method, URI and headers of a client request, for issuing the same request
+// for other byte ranges (prefetches)
+#[derive(Debug, Clone)]
+pub struct RequestTemplate {
+    method: hyper::Method,
+    uri: hyper::Uri,
+    headers: HeaderMap,
+}
+
+impl RequestTemplate {
+    pub fn from_request(req: &Request<Body>) -> Self {
+        Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+        }
+    }
+
+    pub fn with_range(&self, start: u64, end: u64) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.headers_mut() = self.headers.clone();
+        let range = format!("bytes={}-{}", start, end);
+        req.headers_mut().insert(
+            hyper::header::RANGE,
+            HeaderValue::from_str(&range).expect("Infallible: formatted from integers"),
+        );
+        req
+    }
+}
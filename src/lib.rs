@@ -0,0 +1,45 @@
+// Chunking MITM proxy for video streaming. Embed it with ProxyBuilder:
+//
+//     let proxy = ProxyBuilder::new(config).build()?;
+//     let addr = proxy.default_addr();
+//     let bound = proxy.bind(addr)?;
+//     let shutdown = bound.shutdown_handle();
+//     bound.run().await?;
+
+pub mod builder;
+pub mod config;
+pub mod har;
+pub mod replay;
+pub mod stream_key;
+
+// Used by the command-line tool and the integration tests; not a stable API
+#[doc(hidden)]
+pub mod bench;
+#[doc(hidden)]
+pub mod connector;
+#[doc(hidden)]
+pub mod doctor;
+#[doc(hidden)]
+pub mod pool;
+#[doc(hidden)]
+pub mod proxy;
+#[doc(hidden)]
+pub mod resource;
+#[doc(hidden)]
+pub mod sites;
+
+mod dns;
+mod download;
+#[cfg(feature = "fault-injection")]
+mod faults;
+mod hedge;
+mod inflight;
+mod ratelimit;
+mod scheduler;
+mod tee;
+
+pub use builder::{BoundProxy, Proxy, ProxyBuilder, ShutdownHandle};
+pub use config::Config;
+
+// Error type of the public API
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -0,0 +1,391 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+// Budget when none is configured
+pub const DEFAULT_BUDGET: u64 = 1024 * 1024 * 1024; // 1GB
+
+// Smallest buffer the pool hands out
+const MIN_CLASS_BITS: u32 = 16;
+pub const MIN_BUFFER_SIZE: u64 = 1 << MIN_CLASS_BITS; // 64KB
+// Largest chunk buffer handed out, pooled or not
+pub const MAX_BUFFER_SIZE: u64 = 4 * 1024 * 1024 * 1024; // 4GB
+// Largest power of two with its own size classes; bigger buffers are never pooled
+const MAX_CLASS_BITS: u32 = 40;
+// Each power of two is split into this many size classes, so rounding a
+// request up wastes at most a quarter of it (and nothing for sizes like
+// 2.5MB, 5MB or 10MB)
+const STEPS: usize = 4;
+// Free lists per size class, so concurrent returns rarely meet on one lock
+const SHARDS: usize = 4;
+
+// Size class index and size for a buffer of at least `size` bytes
+fn size_class(size: usize) -> Option<(usize, usize)> {
+    let size = size.max(1 << MIN_CLASS_BITS);
+    let bits = usize::BITS - 1 - size.leading_zeros();
+    let step = 1usize << (bits - 2);
+    let rounded = size.div_ceil(step).checked_mul(step)?;
+    // Rounding up may reach the next power of two
+    let bits = usize::BITS - 1 - rounded.leading_zeros();
+    if bits > MAX_CLASS_BITS {
+        return None;
+    }
+    let index = (bits - MIN_CLASS_BITS) as usize * STEPS + (rounded >> (bits - 2)) - STEPS;
+    Some((index, rounded))
+}
+
+// Bytes of the budget a buffer of `size` bytes takes; None when it is too
+// big to pool
+pub fn buffer_size(size: usize) -> Option<usize> {
+    size_class(size).map(|(_, class_size)| class_size)
+}
+
+// Counters behind PoolStats; updated without locks
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    waits: AtomicU64,
+    timeouts: AtomicU64,
+    evictions: AtomicU64,
+    overflows: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoolStats {
+    // Buffers reused from a free list
+    pub hits: u64,
+    // Buffers newly allocated within the budget
+    pub misses: u64,
+    // Requests that had to wait for memory, and those that gave up
+    pub waits: u64,
+    pub timeouts: u64,
+    // Free buffers dropped to make room for another size
+    pub evictions: u64,
+    // Buffers handed out beyond the budget, and the bytes they hold now
+    pub overflows: u64,
+    pub overflow: u64,
+    // Bytes handed out or kept in free lists, and the part kept free
+    pub reserved: u64,
+    pub free: u64,
+    pub budget: u64,
+}
+
+impl PoolStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64 * 100.0
+    }
+}
+
+// Chunk buffers shared by every download, limited to a byte budget. Buffers
+// handed out and buffers kept for reuse both count against it. When it is
+// used up, free buffers of other sizes are dropped to make room, and after
+// that requests wait asynchronously until a buffer comes back.
+#[derive(Debug)]
+pub struct BufferPool {
+    enabled: bool,
+    budget: u64,
+    // Free buffers are kept up to this many bytes; the rest are released
+    max_free: u64,
+    // Buffers beyond the budget may hold up to this many bytes
+    max_overflow: u64,
+    reserved: AtomicU64,
+    free: AtomicU64,
+    overflow: AtomicU64,
+    // Size class -> shards of free buffers
+    classes: Vec<[Mutex<Vec<Vec<u8>>>; SHARDS]>,
+    next_shard: AtomicUsize,
+    released: Notify,
+    counters: Counters,
+}
+
+impl BufferPool {
+    // A disabled pool allocates every buffer on its own, outside any budget
+    pub fn new(enabled: bool, budget: u64) -> Arc<Self> {
+        let classes = (MIN_CLASS_BITS..=MAX_CLASS_BITS)
+            .flat_map(|_| 0..STEPS)
+            .map(|_| Default::default())
+            .collect();
+        Arc::new(Self {
+            enabled,
+            budget,
+            max_free: budget / 2,
+            max_overflow: budget / 4,
+            reserved: AtomicU64::new(0),
+            free: AtomicU64::new(0),
+            overflow: AtomicU64::new(0),
+            classes,
+            next_shard: AtomicUsize::new(0),
+            released: Notify::new(),
+            counters: Counters::default(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Buffer of `size` bytes, waiting up to `wait` for budget to free up.
+    // None when there was none in time, or the size can never fit.
+    pub async fn acquire(self: &Arc<Self>, size: usize, wait: Duration) -> Option<PooledBuffer> {
+        if !self.enabled {
+            return Some(PooledBuffer::unpooled(size));
+        }
+        let (_, class_size) = size_class(size)?;
+        if class_size as u64 > self.budget {
+            return None;
+        }
+        if let Some(buffer) = self.try_acquire(size) {
+            return Some(buffer);
+        }
+
+        self.counters.waits.fetch_add(1, Ordering::Relaxed);
+        let deadline = Instant::now() + wait;
+        loop {
+            // Registered before trying, so a release in between is not missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if let Some(buffer) = self.try_acquire(size) {
+                return Some(buffer);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                self.counters.timeouts.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        }
+    }
+
+    // Buffer of `size` bytes if the budget allows one right now
+    pub fn try_acquire(self: &Arc<Self>, size: usize) -> Option<PooledBuffer> {
+        if !self.enabled {
+            return Some(PooledBuffer::unpooled(size));
+        }
+        let (index, class_size) = size_class(size)?;
+
+        let data = match self.pop(index) {
+            Some(mut data) => {
+                self.free.fetch_sub(class_size as u64, Ordering::AcqRel);
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                data.resize(size, 0);
+                data
+            }
+            None => {
+                while !self.reserve(class_size as u64) {
+                    if !self.evict() {
+                        return None;
+                    }
+                }
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                let mut data = Vec::with_capacity(class_size);
+                data.resize(size, 0);
+                data
+            }
+        };
+        Some(PooledBuffer {
+            data,
+            charged: class_size,
+            pool: Some(self.clone()),
+            overflow: false,
+        })
+    }
+
+    // Buffer beyond the budget for a client that can't wait any longer. These
+    // hold at most a quarter of the budget on top of it; None when that is
+    // used up too.
+    pub fn acquire_overflow(self: &Arc<Self>, size: usize) -> Option<PooledBuffer> {
+        if !self.enabled {
+            return Some(PooledBuffer::unpooled(size));
+        }
+        self.overflow
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |overflow| {
+                overflow.checked_add(size as u64).filter(|total| *total <= self.max_overflow)
+            })
+            .ok()?;
+        self.counters.overflows.fetch_add(1, Ordering::Relaxed);
+        Some(PooledBuffer {
+            data: vec![0u8; size],
+            charged: size,
+            pool: Some(self.clone()),
+            overflow: true,
+        })
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            waits: self.counters.waits.load(Ordering::Relaxed),
+            timeouts: self.counters.timeouts.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            overflows: self.counters.overflows.load(Ordering::Relaxed),
+            overflow: self.overflow.load(Ordering::Acquire),
+            reserved: self.reserved.load(Ordering::Acquire),
+            free: self.free.load(Ordering::Acquire),
+            budget: self.budget,
+        }
+    }
+
+    pub fn print_stats(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        let stats = self.stats();
+        eprintln!("Memory Pool Stats:");
+        eprintln!(
+            "  {} hits, {} misses, {:.1}% hit rate",
+            stats.hits,
+            stats.misses,
+            stats.hit_rate()
+        );
+        eprintln!(
+            "  {}MB of {}MB budget in use ({}MB free for reuse), {} waits, {} timeouts, {} evictions",
+            stats.reserved / 1024 / 1024,
+            stats.budget / 1024 / 1024,
+            stats.free / 1024 / 1024,
+            stats.waits,
+            stats.timeouts,
+            stats.evictions
+        );
+        if stats.overflows > 0 {
+            eprintln!(
+                "  {} buffers allocated beyond the budget, {}MB of them in use",
+                stats.overflows,
+                stats.overflow / 1024 / 1024
+            );
+        }
+    }
+
+    // Take `bytes` of the budget if they are left
+    fn reserve(&self, bytes: u64) -> bool {
+        self.reserved
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |reserved| {
+                reserved.checked_add(bytes).filter(|total| *total <= self.budget)
+            })
+            .is_ok()
+    }
+
+    fn pop(&self, index: usize) -> Option<Vec<u8>> {
+        let start = self.next_shard.fetch_add(1, Ordering::Relaxed);
+        (0..SHARDS).find_map(|i| self.classes[index][(start + i) % SHARDS].lock().unwrap().pop())
+    }
+
+    // Drop one free buffer, largest sizes first, and give its bytes back to
+    // the budget. False when nothing is free.
+    fn evict(&self) -> bool {
+        for index in (0..self.classes.len()).rev() {
+            if let Some(data) = self.pop(index) {
+                let class_size = class_size_of(index) as u64;
+                self.free.fetch_sub(class_size, Ordering::AcqRel);
+                self.reserved.fetch_sub(class_size, Ordering::AcqRel);
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+                drop(data);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Keep a returned buffer for reuse while free buffers stay under
+    // max_free; otherwise release its bytes
+    fn release(&self, data: Vec<u8>, charged: usize) {
+        let kept = data.capacity() >= charged
+            && self
+                .free
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |free| {
+                    Some(free + charged as u64).filter(|total| *total <= self.max_free)
+                })
+                .is_ok();
+        match size_class(charged) {
+            Some((index, _)) if kept => {
+                let shard = self.next_shard.fetch_add(1, Ordering::Relaxed) % SHARDS;
+                self.classes[index][shard].lock().unwrap().push(data);
+            }
+            _ => {
+                if kept {
+                    self.free.fetch_sub(charged as u64, Ordering::AcqRel);
+                }
+                self.reserved.fetch_sub(charged as u64, Ordering::AcqRel);
+            }
+        }
+        self.released.notify_waiters();
+    }
+}
+
+fn class_size_of(index: usize) -> usize {
+    let bits = MIN_CLASS_BITS as usize + index / STEPS;
+    (STEPS + index % STEPS) << (bits - 2)
+}
+
+// A buffer from the pool, given back when dropped. Unpooled buffers are
+// simply freed.
+#[derive(Default)]
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    // Bytes of the budget, or of the overflow allowance, this buffer holds
+    charged: usize,
+    pool: Option<Arc<BufferPool>>,
+    // Allocated beyond the budget; never kept for reuse
+    overflow: bool,
+}
+
+impl PooledBuffer {
+    // A buffer outside any pool and budget
+    pub fn unpooled(size: usize) -> Self {
+        Self {
+            data: vec![0u8; size],
+            charged: 0,
+            pool: None,
+            overflow: false,
+        }
+    }
+
+    pub fn is_pooled(&self) -> bool {
+        self.pool.is_some() && !self.overflow
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuffer")
+            .field("len", &self.data.len())
+            .field("charged", &self.charged)
+            .field("pooled", &self.is_pooled())
+            .finish()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        match self.pool.take() {
+            Some(pool) if self.overflow => {
+                pool.overflow.fetch_sub(self.charged as u64, Ordering::AcqRel);
+            }
+            Some(pool) => pool.release(std::mem::take(&mut self.data), self.charged),
+            None => {}
+        }
+    }
+}
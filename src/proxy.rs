@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use hyper::server::conn::Http;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use third_wheel::{create_signed_certificate_for_domain, CertificateAuthority};
+use tokio_native_tls::TlsAcceptor;
+
+use crate::connector::Connector;
+
+// Client used for every request forwarded out of an intercepted connection
+pub type UpstreamClient = Client<HttpsConnector<Connector>>;
+
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, hyper::Error>> + Send>>;
+
+type Handler = dyn Fn(Request<Body>, UpstreamClient) -> ResponseFuture + Send + Sync;
+type InterceptRule = dyn Fn(&str) -> bool + Send + Sync;
+type BoxError = Box<dyn Error + Send + Sync>;
+
+// HTTP proxy that answers CONNECT requests. Hosts accepted by the intercept rule
+// are decrypted and passed through the handler, everything else is tunnelled
+// byte-for-byte so it keeps real end-to-end TLS.
+pub struct ProxyServer {
+    ca: CertificateAuthority,
+    connector: Connector,
+    client: UpstreamClient,
+    handler: Box<Handler>,
+    intercept: Box<InterceptRule>,
+    acceptors: Mutex<HashMap<String, TlsAcceptor>>,
+}
+
+impl ProxyServer {
+    pub fn new<H>(
+        ca: CertificateAuthority,
+        connector: Connector,
+        pool_size: usize,
+        handler: H,
+    ) -> Result<Self, native_tls::Error>
+    where
+        H: Fn(Request<Body>, UpstreamClient) -> ResponseFuture + Send + Sync + 'static,
+    {
+        Ok(Self {
+            ca,
+            client: build_client(connector.clone(), pool_size)?,
+            connector,
+            handler: Box::new(handler),
+            intercept: Box::new(|_| true),
+            acceptors: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Only decrypt connections whose host matches the rule (default: all hosts)
+    pub fn intercept_if<F>(mut self, rule: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.intercept = Box::new(rule);
+        self
+    }
+
+    // Bind to a socket address. Returns the address actually bound to, and the
+    // future that runs the server until `shutdown` completes.
+    pub fn bind<S>(
+        self,
+        addr: SocketAddr,
+        shutdown: S,
+    ) -> Result<(SocketAddr, impl Future<Output = Result<(), hyper::Error>>), hyper::Error>
+    where
+        S: Future<Output = ()> + Send + 'static,
+    {
+        let proxy = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let proxy = proxy.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let proxy = proxy.clone();
+                    async move { Ok::<_, Infallible>(proxy.handle_connect(req).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        Ok((server.local_addr(), server.with_graceful_shutdown(shutdown)))
+    }
+
+    async fn handle_connect(self: Arc<Self>, req: Request<Body>) -> Response<Body> {
+        if req.method() != Method::CONNECT {
+            return status_response(StatusCode::BAD_REQUEST);
+        }
+
+        let (host, port) = match req.uri().authority() {
+            Some(authority) => (
+                authority.host().to_string(),
+                authority.port_u16().unwrap_or(443),
+            ),
+            None => {
+                eprintln!("Warning: CONNECT request without host: {}", req.uri());
+                return status_response(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        if (self.intercept)(&host) {
+            tokio::spawn(async move {
+                match hyper::upgrade::on(req).await {
+                    Ok(upgraded) => {
+                        if let Err(e) = self.intercept_connection(upgraded, host.clone(), port).await {
+                            eprintln!("Intercepted connection to {}:{} failed: {}", host, port, e);
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to upgrade connection to {}:{}: {}", host, port, e),
+                }
+            });
+            return status_response(StatusCode::OK);
+        }
+
+        // Connect before answering so the client sees a failed tunnel as such
+        let mut upstream = match self.connector.connect(&host, port).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Tunnel to {}:{} failed: {}", host, port, e);
+                return status_response(StatusCode::BAD_GATEWAY);
+            }
+        };
+
+        eprintln!("Tunnelling {}:{} (not intercepted)", host, port);
+        tokio::spawn(async move {
+            match hyper::upgrade::on(req).await {
+                Ok(mut upgraded) => {
+                    if let Err(e) = tokio::io::copy_bidirectional(&mut upgraded, &mut upstream).await
+                    {
+                        eprintln!("Tunnel to {}:{} closed with error: {}", host, port, e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to upgrade connection to {}:{}: {}", host, port, e),
+            }
+        });
+        status_response(StatusCode::OK)
+    }
+
+    async fn intercept_connection(
+        self: Arc<Self>,
+        upgraded: Upgraded,
+        host: String,
+        port: u16,
+    ) -> Result<(), BoxError> {
+        let acceptor = self.acceptor_for(&host)?;
+        let client_stream = acceptor.accept(upgraded).await?;
+
+        let authority = format!("{}:{}", host, port);
+        let service = service_fn(move |mut req: Request<Body>| {
+            // Requests inside the tunnel are origin-form; make them absolute for the client
+            let path = req
+                .uri()
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/");
+            let uri = format!("https://{}{}", authority, path).parse::<Uri>();
+            let proxy = self.clone();
+            async move {
+                match uri {
+                    Ok(uri) => *req.uri_mut() = uri,
+                    Err(_) => return Ok(status_response(StatusCode::BAD_REQUEST)),
+                }
+                req.headers_mut().remove("proxy-connection");
+                (proxy.handler)(req, proxy.client.clone()).await
+            }
+        });
+
+        Http::new()
+            .http1_only(true)
+            .serve_connection(client_stream, service)
+            .await?;
+        Ok(())
+    }
+
+    // Certificates are signed once per host and reused for later connections
+    fn acceptor_for(&self, host: &str) -> Result<TlsAcceptor, BoxError> {
+        let mut acceptors = self.acceptors.lock().unwrap();
+        if let Some(acceptor) = acceptors.get(host) {
+            return Ok(acceptor.clone());
+        }
+
+        let cert = create_signed_certificate_for_domain(host, &self.ca)?;
+        let identity = native_tls::Identity::from_pkcs8(
+            &cert.to_pem()?,
+            &self.ca.key.private_key_to_pem_pkcs8()?,
+        )?;
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+        acceptors.insert(host.to_string(), acceptor.clone());
+        Ok(acceptor)
+    }
+}
+
+// HTTP(S) client whose connections are all opened through the given connector
+pub fn build_client(connector: Connector, pool_size: usize) -> Result<UpstreamClient, native_tls::Error> {
+    let tls = native_tls::TlsConnector::new()?;
+    let https = HttpsConnector::from((connector, tls.into()));
+    Ok(Client::builder()
+        .pool_max_idle_per_host(pool_size)
+        .build(https))
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = status;
+    res
+}
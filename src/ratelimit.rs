@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use futures_util::stream;
+use hyper::body::HttpBody;
+use hyper::{Body, Response};
+use tokio::time::Instant;
+
+// Token bucket holding up to one second of traffic. Callers take what they
+// read and sleep off any debt, so a burst is paid for by waiting afterwards.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    // `rate` in bytes per second; must be non-zero
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    // How long the caller must wait after consuming `bytes`
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.rate) - bytes as f64;
+        state.updated = now;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+}
+
+// Global and per-stream caps on upstream read throughput
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    global: Option<Arc<TokenBucket>>,
+    per_stream: Option<u64>,
+    // Buckets live as long as some download of the stream holds them
+    streams: Mutex<HashMap<String, Weak<TokenBucket>>>,
+}
+
+impl RateLimiter {
+    // Rates in bytes per second; None leaves that level unlimited
+    pub fn new(global: Option<u64>, per_stream: Option<u64>) -> Self {
+        Self {
+            global: global.filter(|r| *r > 0).map(|r| Arc::new(TokenBucket::new(r))),
+            per_stream: per_stream.filter(|r| *r > 0),
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn for_stream(&self, key: &str) -> StreamLimit {
+        let stream = self.per_stream.map(|rate| {
+            let mut streams = self.streams.lock().unwrap();
+            if let Some(bucket) = streams.get(key).and_then(Weak::upgrade) {
+                return bucket;
+            }
+            streams.retain(|_, bucket| bucket.strong_count() > 0);
+            let bucket = Arc::new(TokenBucket::new(rate));
+            streams.insert(key.to_string(), Arc::downgrade(&bucket));
+            bucket
+        });
+
+        StreamLimit {
+            global: self.global.clone(),
+            stream,
+            cap: None,
+        }
+    }
+}
+
+// The buckets one download draws from
+#[derive(Debug, Clone, Default)]
+pub struct StreamLimit {
+    global: Option<Arc<TokenBucket>>,
+    stream: Option<Arc<TokenBucket>>,
+    // Extra bucket shared by one kind of download, e.g. background downloads
+    cap: Option<Arc<TokenBucket>>,
+}
+
+impl StreamLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.global.is_none() && self.stream.is_none() && self.cap.is_none()
+    }
+
+    // Also draw from `cap`
+    pub fn with_cap(mut self, cap: Arc<TokenBucket>) -> Self {
+        self.cap = Some(cap);
+        self
+    }
+
+    // Account for `bytes` just read, waiting until every bucket allows them
+    pub async fn consume(&self, bytes: usize) {
+        let wait = [&self.global, &self.stream, &self.cap]
+            .into_iter()
+            .flatten()
+            .map(|bucket| bucket.take(bytes))
+            .max()
+            .unwrap_or(Duration::ZERO);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // Pace the body of a response passed straight through to the client
+    pub fn throttle(self, response: Response<Body>) -> Response<Body> {
+        if self.is_unlimited() {
+            return response;
+        }
+
+        let (parts, body) = response.into_parts();
+        let paced = stream::unfold((body, self), |(mut body, limit)| async move {
+            let data = body.data().await?;
+            if let Ok(bytes) = &data {
+                limit.consume(bytes.len()).await;
+            }
+            Some((data, (body, limit)))
+        });
+        Response::from_parts(parts, Body::wrap_stream(paced))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_holds_one_second_of_traffic() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        // Debt is paid off at the rate
+        assert_eq!(bucket.take(500), Duration::from_millis(500));
+
+        // Idle time refills no more than one second's worth
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert_eq!(bucket.take(100), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_over_time() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(bucket.take(250), Duration::ZERO);
+        assert_eq!(bucket.take(250), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn consume_waits_for_the_slowest_bucket() {
+        let limiter = RateLimiter::new(Some(4000), Some(1000));
+        let limit = limiter.for_stream("a");
+        let started = Instant::now();
+        limit.consume(1500).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+
+        // Downloads of one stream share its bucket; other streams have their own
+        limiter.for_stream("a").consume(500).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+        limiter.for_stream("b").consume(1000).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_or_missing_rates_are_unlimited() {
+        for limiter in [RateLimiter::new(None, None), RateLimiter::new(Some(0), Some(0))] {
+            let limit = limiter.for_stream("a");
+            assert!(limit.is_unlimited());
+            let started = Instant::now();
+            limit.consume(usize::MAX).await;
+            assert_eq!(started.elapsed(), Duration::ZERO);
+        }
+        assert!(RateLimiter::new(None, None).streams.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use futures_util::stream;
+use hyper::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use serde::Deserialize;
+
+use crate::har::redacted_url;
+use crate::resource::{parse_content_range, parse_range};
+use crate::stream_key::StreamKeyRules;
+use crate::Error;
+
+// Bodies are paced in slices of this size when timing is reproduced
+const PACE_SLICE: usize = 64 * 1024;
+
+// The parts of a HAR file that replay needs
+#[derive(Debug, Deserialize)]
+struct Har {
+    log: Log,
+}
+
+#[derive(Debug, Deserialize)]
+struct Log {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    request: EntryRequest,
+    response: EntryResponse,
+    #[serde(default)]
+    timings: Timings,
+}
+
+#[derive(Debug, Deserialize)]
+struct EntryRequest {
+    method: String,
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EntryResponse {
+    status: u16,
+    #[serde(default)]
+    headers: Vec<Header>,
+    content: Content,
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    #[serde(default)]
+    mime_type: String,
+    text: Option<String>,
+    encoding: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Timings {
+    #[serde(default)]
+    wait: f64,
+    #[serde(default)]
+    receive: f64,
+}
+
+// Recorded bytes of a resource starting at `start`
+#[derive(Debug)]
+struct Segment {
+    start: u64,
+    data: Bytes,
+    // Time to first byte and transfer rate (bytes/s) when it was recorded
+    wait: Duration,
+    rate: Option<f64>,
+}
+
+impl Segment {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+}
+
+#[derive(Debug, Default)]
+struct Resource {
+    mime: String,
+    length: Option<u64>,
+    segments: Vec<Segment>,
+}
+
+impl Resource {
+    // Recorded bytes from `start` up to `end` (inclusive), as far as they go
+    // without a gap, and the segment holding `start`
+    fn read(&self, start: u64, end: u64) -> Option<(Bytes, &Segment)> {
+        let mut out = BytesMut::new();
+        let mut first = None;
+        let mut pos = start;
+        while pos <= end {
+            let Some(segment) = self
+                .segments
+                .iter()
+                .filter(|s| s.start <= pos && s.end() > pos)
+                .max_by_key(|s| s.end())
+            else {
+                break;
+            };
+            let from = (pos - segment.start) as usize;
+            // `end` is u64::MAX for an open range of unknown length
+            let to = end.saturating_add(1).min(segment.end()) - segment.start;
+            out.extend_from_slice(&segment.data[from..to as usize]);
+            first.get_or_insert(segment);
+            pos = segment.start + to;
+        }
+        first.map(|segment| (out.freeze(), segment))
+    }
+}
+
+// Answers upstream requests from a HAR recorded with bodies, so playback can
+// be reproduced with no network. Ranges are served from whatever recorded
+// bytes cover them, so a different chunk size still replays.
+#[derive(Debug)]
+pub struct Replay {
+    path: PathBuf,
+    stream_keys: StreamKeyRules,
+    // Stream key -> recorded bytes
+    resources: HashMap<String, Resource>,
+    // Reproduce recorded time to first byte and transfer rate
+    timing: bool,
+}
+
+impl Replay {
+    pub fn load(path: impl Into<PathBuf>, stream_keys: StreamKeyRules, timing: bool) -> Result<Self, Error> {
+        let path = path.into();
+        let har: Har = serde_json::from_slice(&std::fs::read(&path)?)?;
+
+        let mut resources: HashMap<String, Resource> = HashMap::new();
+        let mut responses = 0;
+        for entry in har.log.entries {
+            if entry.request.method != Method::GET.as_str()
+                || !matches!(entry.response.status, 200 | 206)
+            {
+                continue;
+            }
+            let Some(text) = entry.response.content.text else {
+                continue;
+            };
+            let data = match entry.response.content.encoding.as_deref() {
+                Some("base64") => base64::engine::general_purpose::STANDARD.decode(text)?,
+                _ => text.into_bytes(),
+            };
+            let Ok(uri) = entry.request.url.parse::<Uri>() else {
+                continue;
+            };
+
+            let header = |name: &str| {
+                entry
+                    .response
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case(name))
+                    .map(|h| h.value.as_str())
+            };
+            let (start, length) = match entry.response.status {
+                206 => match header(CONTENT_RANGE.as_str()).and_then(parse_content_range) {
+                    Some((start, _, length)) => (start, length),
+                    None => continue,
+                },
+                _ => (0, Some(data.len() as u64)),
+            };
+
+            let receive = entry.timings.receive / 1000.0;
+            let resource = resources.entry(stream_keys.key_for(&uri)).or_default();
+            resource.mime = entry.response.content.mime_type;
+            resource.length = resource.length.or(length);
+            resource.segments.push(Segment {
+                start,
+                rate: (receive > 0.0).then(|| data.len() as f64 / receive),
+                data: data.into(),
+                wait: Duration::from_secs_f64(entry.timings.wait.max(0.0) / 1000.0),
+            });
+            responses += 1;
+        }
+
+        if responses == 0 {
+            return Err("no response bodies to replay; record with --record-bodies".into());
+        }
+        Ok(Self {
+            path,
+            stream_keys,
+            resources,
+            timing,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Number of distinct resources in the recording
+    pub fn resources(&self) -> usize {
+        self.resources.len()
+    }
+
+    pub fn reproduces_timing(&self) -> bool {
+        self.timing
+    }
+
+    // Answer `req` as the origin did when it was recorded
+    pub(crate) async fn respond(&self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        let url = redacted_url(req.uri());
+        let range = req
+            .headers()
+            .get("Range")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let resource = url
+            .parse::<Uri>()
+            .ok()
+            .filter(|_| req.method() == Method::GET)
+            .and_then(|uri| self.resources.get(&self.stream_keys.key_for(&uri)));
+        let Some(resource) = resource else {
+            eprintln!("Replay miss: {} {} (not recorded)", req.method(), url);
+            return Ok(status_response(StatusCode::NOT_FOUND));
+        };
+
+        let requested = match range.as_deref() {
+            Some(range) => match parse_range(range) {
+                Some(requested) => Some(requested),
+                None => {
+                    eprintln!("Replay miss: {} with unsupported Range {}", url, range);
+                    return Ok(status_response(StatusCode::NOT_FOUND));
+                }
+            },
+            None => None,
+        };
+        let (start, end) = requested.unwrap_or((0, None));
+
+        if let Some(length) = resource.length.filter(|&length| start >= length) {
+            let mut response = status_response(StatusCode::RANGE_NOT_SATISFIABLE);
+            response
+                .headers_mut()
+                .insert(CONTENT_RANGE, format!("bytes */{}", length).parse().unwrap());
+            return Ok(response);
+        }
+
+        let last = resource.length.map_or(u64::MAX, |length| length - 1);
+        let end = end.unwrap_or(last).min(last);
+        let served = resource.read(start, end).filter(|(data, _)| {
+            // Closed ranges and whole bodies must be fully recorded; an
+            // open-ended range gets what was recorded from its start
+            let read_end = start + data.len() as u64 - 1;
+            read_end == end || requested.is_some_and(|(_, end)| end.is_none())
+        });
+        let Some((data, segment)) = served else {
+            eprintln!(
+                "Replay miss: {} {} (bytes not recorded)",
+                url,
+                range.as_deref().unwrap_or("whole body")
+            );
+            return Ok(status_response(StatusCode::NOT_FOUND));
+        };
+
+        let mut response = Response::new(Body::empty());
+        let headers = response.headers_mut();
+        if let Ok(mime) = resource.mime.parse() {
+            headers.insert(CONTENT_TYPE, mime);
+        }
+        headers.insert(ACCEPT_RANGES, "bytes".parse().unwrap());
+        headers.insert(CONTENT_LENGTH, data.len().into());
+        if requested.is_some() {
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            let length = resource.length.map_or_else(|| "*".to_string(), |length| length.to_string());
+            let content_range = format!("bytes {}-{}/{}", start, start + data.len() as u64 - 1, length);
+            response.headers_mut().insert(CONTENT_RANGE, content_range.parse().unwrap());
+        }
+
+        if !self.timing {
+            *response.body_mut() = Body::from(data);
+            return Ok(response);
+        }
+
+        tokio::time::sleep(segment.wait).await;
+        let rate = segment.rate;
+        let paced = stream::unfold(data, move |mut data| async move {
+            if data.is_empty() {
+                return None;
+            }
+            let slice = data.split_to(data.len().min(PACE_SLICE));
+            if let Some(rate) = rate {
+                tokio::time::sleep(Duration::from_secs_f64(slice.len() as f64 / rate)).await;
+            }
+            Some((Ok::<_, hyper::Error>(slice), data))
+        });
+        *response.body_mut() = Body::wrap_stream(paced);
+        Ok(response)
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use hyper::header::{HeaderMap, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE};
+use hyper::{StatusCode, Uri};
+
+// Upper bound on remembered resources; the least recently seen is dropped
+const MAX_RESOURCES: usize = 512;
+
+// What is known about the resource behind a stream
+#[derive(Debug, Clone, Default)]
+pub struct ResourceInfo {
+    // Total size in bytes
+    pub length: Option<u64>,
+    pub mime: Option<String>,
+}
+
+impl ResourceInfo {
+    // Unknown types count as media so they keep being chunked
+    pub fn is_media(&self) -> bool {
+        match self.mime.as_deref() {
+            Some(mime) => {
+                let mime = mime.to_ascii_lowercase();
+                mime.starts_with("video/")
+                    || mime.starts_with("audio/")
+                    || mime.starts_with("application/octet-stream")
+                    || mime.starts_with("application/vnd.yt-ump")
+            }
+            None => true,
+        }
+    }
+
+    // Largest valid byte offset, when the length is known
+    pub fn last_byte(&self) -> Option<u64> {
+        self.length.map(|length| length.saturating_sub(1))
+    }
+}
+
+// Resource length and type per stream key, from URL parameters (googlevideo
+// `clen` and `mime`) and from the headers of earlier responses
+#[derive(Debug, Default)]
+pub struct ResourceTracker {
+    learned: Mutex<HashMap<String, (ResourceInfo, Instant)>>,
+}
+
+impl ResourceTracker {
+    pub fn lookup(&self, key: &str, uri: &Uri) -> ResourceInfo {
+        let mut info = self
+            .learned
+            .lock()
+            .unwrap()
+            .get_mut(key)
+            .map(|(info, seen)| {
+                *seen = Instant::now();
+                info.clone()
+            })
+            .unwrap_or_default();
+
+        // URL parameters describe this exact request, so they win
+        if let Some(length) = query_param(uri, "clen").and_then(|v| v.parse().ok()) {
+            info.length = Some(length);
+        }
+        if let Some(mime) = query_param(uri, "mime") {
+            info.mime = Some(mime.replace("%2F", "/").replace("%2f", "/"));
+        }
+        info
+    }
+
+    // Remember length and type from an upstream response
+    pub fn learn(&self, key: &str, status: StatusCode, headers: &HeaderMap) {
+        let length = match status {
+            StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => header_str(headers, CONTENT_RANGE)
+                .and_then(|value| value.rsplit_once('/'))
+                .and_then(|(_, total)| total.trim().parse().ok()),
+            StatusCode::OK => header_str(headers, CONTENT_LENGTH).and_then(|v| v.trim().parse().ok()),
+            _ => None,
+        };
+        let mime = header_str(headers, CONTENT_TYPE)
+            .filter(|_| status.is_success())
+            .map(|value| value.split(';').next().unwrap_or(value).trim().to_string());
+        if length.is_none() && mime.is_none() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut learned = self.learned.lock().unwrap();
+        if !learned.contains_key(key) && learned.len() >= MAX_RESOURCES {
+            if let Some(oldest) = learned
+                .iter()
+                .min_by_key(|(_, (_, seen))| *seen)
+                .map(|(key, _)| key.clone())
+            {
+                learned.remove(&oldest);
+            }
+        }
+
+        let (info, seen) = learned.entry(key.to_string()).or_insert_with(|| (ResourceInfo::default(), now));
+        *seen = now;
+        if length.is_some() {
+            info.length = length;
+        }
+        if mime.is_some() {
+            info.mime = mime;
+        }
+    }
+}
+
+// "bytes start-end/length" -> (start, end, length); length is None for "*"
+pub fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, length) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (end >= start).then_some((start, end, length.trim().parse().ok()))
+}
+
+// "bytes=start-end" or "bytes=start-"; None for anything else, including an
+// end before the start
+pub fn parse_range(range: &str) -> Option<(u64, Option<u64>)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse().ok()?;
+    if end.is_empty() {
+        return Some((start, None));
+    }
+    let end = end.parse().ok()?;
+    (end >= start).then_some((start, Some(end)))
+}
+
+fn query_param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(param, _)| *param == name)
+        .map(|(_, value)| value)
+}
+
+fn header_str(headers: &HeaderMap, name: hyper::header::HeaderName) -> Option<&str> {
+    headers.get(name)?.to_str().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-"), Some((0, None)));
+        assert_eq!(parse_range("bytes=100-199"), Some((100, Some(199))));
+        assert_eq!(parse_range("bytes=5-5"), Some((5, Some(5))));
+        for range in ["bytes=100-50", "bytes=-500", "bytes=0-1,5-9", "bytes=x-", "items=0-", ""] {
+            assert_eq!(parse_range(range), None, "{}", range);
+        }
+    }
+
+    #[test]
+    fn content_ranges() {
+        assert_eq!(parse_content_range("bytes 0-99/1000"), Some((0, 99, Some(1000))));
+        assert_eq!(parse_content_range("bytes 100-199/*"), Some((100, 199, None)));
+        for value in ["bytes 200-100/1000", "bytes */1000", "bytes 0-99", "0-99/1000"] {
+            assert_eq!(parse_content_range(value), None, "{}", value);
+        }
+    }
+}
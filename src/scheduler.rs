@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, watch};
+
+// Hands out upstream download slots. Foreground fetches (a client is waiting
+// for bytes nobody was downloading yet) start immediately and pause prefetches
+// while they run; prefetches queue for the slots left over, shared evenly
+// between streams so the audio and video of one video advance together.
+#[derive(Debug)]
+pub struct Scheduler {
+    max_slots: usize,
+    state: Mutex<SchedulerState>,
+    foreground: watch::Sender<usize>,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    // Prefetch slots held, per stream key
+    running: HashMap<String, usize>,
+    // Prefetches a client has started reading from
+    promoted: usize,
+    waiting: Vec<Waiter>,
+    next_ticket: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    stream: String,
+    ticket: u64,
+    grant: oneshot::Sender<Slot>,
+}
+
+impl Scheduler {
+    pub fn new(max_slots: usize) -> Arc<Self> {
+        let (foreground, _) = watch::channel(0);
+        Arc::new(Self {
+            max_slots: max_slots.max(1),
+            state: Mutex::new(SchedulerState::default()),
+            foreground,
+        })
+    }
+
+    // Slot for a fetch a client is blocked on; never waits
+    pub fn foreground(self: &Arc<Self>) -> Slot {
+        self.foreground.send_modify(|count| *count += 1);
+        Slot {
+            scheduler: self.clone(),
+            kind: SlotKind::Foreground,
+        }
+    }
+
+    // Wait for a free prefetch slot. Streams holding the fewest slots are
+    // served first, then in arrival order.
+    pub async fn prefetch(self: &Arc<Self>, stream: &str) -> Slot {
+        let (grant, granted) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.waiting.push(Waiter {
+                stream: stream.to_string(),
+                ticket,
+                grant,
+            });
+        }
+        self.dispatch();
+
+        match granted.await {
+            Ok(slot) => slot,
+            // The scheduler never drops a waiter without granting it a slot
+            Err(_) => unreachable!("prefetch waiter dropped without a slot"),
+        }
+    }
+
+    // A slot only if one is free right now, for optional extra downloads.
+    // Prefetch-level requests also leave queued prefetches their turn.
+    pub fn try_acquire(self: &Arc<Self>, stream: &str, foreground: bool) -> Option<Slot> {
+        let mut state = self.state.lock().unwrap();
+        if self.held(&state) >= self.max_slots || (!foreground && !state.waiting.is_empty()) {
+            return None;
+        }
+
+        if foreground {
+            drop(state);
+            return Some(self.foreground());
+        }
+        *state.running.entry(stream.to_string()).or_default() += 1;
+        Some(Slot {
+            scheduler: self.clone(),
+            kind: SlotKind::Prefetch(stream.to_string()),
+        })
+    }
+
+    // Resolves once no foreground fetch is running
+    pub async fn foreground_idle(&self) {
+        let mut foreground = self.foreground.subscribe();
+        // The sender lives as long as `self`, so this cannot fail
+        let _ = foreground.wait_for(|count| *count == 0).await;
+    }
+
+    fn held(&self, state: &SchedulerState) -> usize {
+        state.running.values().sum::<usize>() + state.promoted + *self.foreground.borrow()
+    }
+
+    fn dispatch(self: &Arc<Self>) {
+        let mut grants = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            // Waiters whose fetch was cancelled while queued
+            state.waiting.retain(|waiter| !waiter.grant.is_closed());
+            while self.held(&state) < self.max_slots && !state.waiting.is_empty() {
+                let next = state
+                    .waiting
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, waiter)| {
+                        let running = state.running.get(&waiter.stream).copied().unwrap_or(0);
+                        (running, waiter.ticket)
+                    })
+                    .map(|(index, _)| index)
+                    .expect("Infallible: waiting is not empty");
+                let waiter = state.waiting.swap_remove(next);
+                *state.running.entry(waiter.stream.clone()).or_default() += 1;
+                grants.push((
+                    waiter.grant,
+                    Slot {
+                        scheduler: self.clone(),
+                        kind: SlotKind::Prefetch(waiter.stream),
+                    },
+                ));
+            }
+        }
+
+        // Sent outside the lock: a waiter that went away drops its slot,
+        // which releases it and dispatches again
+        for (grant, slot) in grants {
+            let _ = grant.send(slot);
+        }
+    }
+
+    fn release(self: &Arc<Self>, kind: &SlotKind) {
+        match kind {
+            SlotKind::Foreground => self.foreground.send_modify(|count| *count -= 1),
+            SlotKind::Promoted => self.state.lock().unwrap().promoted -= 1,
+            SlotKind::Prefetch(stream) => {
+                let mut state = self.state.lock().unwrap();
+                if let Some(running) = state.running.get_mut(stream) {
+                    *running -= 1;
+                    if *running == 0 {
+                        state.running.remove(stream);
+                    }
+                }
+            }
+        }
+        self.dispatch();
+    }
+}
+
+#[derive(Debug)]
+enum SlotKind {
+    Foreground,
+    Prefetch(String),
+    Promoted,
+}
+
+// An upstream download slot, released when dropped
+#[derive(Debug)]
+pub struct Slot {
+    scheduler: Arc<Scheduler>,
+    kind: SlotKind,
+}
+
+impl Slot {
+    // Prefetch slots yield to foreground fetches
+    pub fn is_prefetch(&self) -> bool {
+        matches!(self.kind, SlotKind::Prefetch(_))
+    }
+
+    // A client started reading this prefetch: it no longer yields, and no
+    // longer counts against its stream's share
+    pub fn promote(&mut self) {
+        if self.is_prefetch() {
+            self.scheduler.state.lock().unwrap().promoted += 1;
+            let previous = std::mem::replace(&mut self.kind, SlotKind::Promoted);
+            self.scheduler.release(&previous);
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.scheduler.release(&self.kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::JoinHandle;
+
+    // Queue a prefetch for `stream`, after any queued before it
+    async fn queue(scheduler: &Arc<Scheduler>, stream: &'static str) -> JoinHandle<Slot> {
+        let scheduler = scheduler.clone();
+        let waiter = tokio::spawn(async move { scheduler.prefetch(stream).await });
+        settle().await;
+        waiter
+    }
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn foreground_never_waits() {
+        let scheduler = Scheduler::new(1);
+        let prefetch = scheduler.prefetch("a").await;
+        let foreground = scheduler.foreground();
+        let another = scheduler.foreground();
+        assert!(!foreground.is_prefetch());
+        assert!(scheduler.try_acquire("a", true).is_none());
+
+        // Prefetches wait until the foreground fetches are done
+        let idle = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.foreground_idle().await }
+        });
+        drop(prefetch);
+        let queued = queue(&scheduler, "a").await;
+        assert!(!queued.is_finished());
+        drop((foreground, another));
+        assert!(queued.await.unwrap().is_prefetch());
+        idle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn prefetch_slots_are_capped() {
+        let scheduler = Scheduler::new(2);
+        let first = scheduler.prefetch("a").await;
+        let _second = scheduler.prefetch("a").await;
+        let third = queue(&scheduler, "a").await;
+        assert!(!third.is_finished());
+        // Optional downloads don't jump the queue
+        assert!(scheduler.try_acquire("b", false).is_none());
+
+        drop(first);
+        third.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn prefetch_is_fair_across_streams() {
+        let scheduler = Scheduler::new(2);
+        let first = scheduler.prefetch("video").await;
+        let _second = scheduler.prefetch("video").await;
+        let more_video = queue(&scheduler, "video").await;
+        let audio = queue(&scheduler, "audio").await;
+
+        // Audio holds no slot, so it goes first although it queued later
+        drop(first);
+        let audio = audio.await.unwrap();
+        settle().await;
+        assert!(!more_video.is_finished());
+
+        drop(audio);
+        more_video.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn promoted_slots_leave_the_stream_share() {
+        let scheduler = Scheduler::new(3);
+        let mut promoted = scheduler.prefetch("video").await;
+        promoted.promote();
+        assert!(!promoted.is_prefetch());
+        let _video = scheduler.prefetch("video").await;
+        let _audio = scheduler.prefetch("audio").await;
+        let video = queue(&scheduler, "video").await;
+        let audio = queue(&scheduler, "audio").await;
+
+        // Both streams count one slot, so the earlier waiter goes first
+        drop(promoted);
+        let _next = video.await.unwrap();
+        settle().await;
+        assert!(!audio.is_finished());
+    }
+}
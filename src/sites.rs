@@ -0,0 +1,365 @@
+use std::sync::Arc;
+use std::time::Duration;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::inflight::RetryPolicy;
+
+// Built-in site categories under [websites] and the host patterns they match.
+// Each category includes the CDN hosts its media bytes come from, since those
+// are the requests that carry Range headers.
+pub const BUILTIN_SITES: &[(&str, &[&str])] = &[
+    (
+        "youtube",
+        &["youtube.com", "*.youtube.com", "youtu.be", "*.googlevideo.com"],
+    ),
+    (
+        "youtube_alternatives",
+        &["yewtu.be", "invidio.us", "*.invidio.us", "piped.video", "*.piped.video"],
+    ),
+    ("vimeo", &["vimeo.com", "*.vimeo.com", "*.vimeocdn.com"]),
+    ("dailymotion", &["dailymotion.com", "*.dailymotion.com", "*.dmcdn.net"]),
+    ("twitch", &["twitch.tv", "*.twitch.tv", "*.ttvnw.net"]),
+];
+
+#[derive(Debug, Clone)]
+enum HostPattern {
+    Exact(String),
+    // Any subdomain of the stored ".suffix", not the suffix host itself
+    Subdomain(String),
+    Regex(Regex),
+}
+
+// A site rule: "example.com" (exact host), "*.example.com" (subdomains) or
+// "re:<regex>" (matched against the host), optionally followed by a path
+// prefix as in "example.com/videos/"
+#[derive(Debug, Clone)]
+pub struct SitePattern {
+    host: HostPattern,
+    path_prefix: Option<String>,
+}
+
+impl SitePattern {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        if let Some(expression) = input.strip_prefix("re:") {
+            let regex = Regex::new(expression)
+                .map_err(|e| format!("Invalid regex in site pattern '{}': {}", input, e))?;
+            return Ok(Self {
+                host: HostPattern::Regex(regex),
+                path_prefix: None,
+            });
+        }
+
+        let rest = input
+            .strip_prefix("https://")
+            .or_else(|| input.strip_prefix("http://"))
+            .unwrap_or(input);
+        let (authority, path_prefix) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], Some(rest[slash..].to_string())),
+            None => (rest, None),
+        };
+        let host = normalize_host(authority);
+        if host.is_empty() {
+            return Err(format!("Invalid site pattern '{}': no host", input));
+        }
+
+        let host = match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostPattern::Subdomain(suffix.to_string()),
+            Some(_) => return Err(format!("Invalid site pattern '{}': use '*.domain'", input)),
+            None => HostPattern::Exact(host),
+        };
+        Ok(Self { host, path_prefix })
+    }
+
+    // `path` is None when only the host is known (CONNECT); a path prefix then
+    // does not rule the host out
+    pub fn matches(&self, host: &str, path: Option<&str>) -> bool {
+        let host = normalize_host(host);
+        let host_matches = match &self.host {
+            HostPattern::Exact(exact) => host == *exact,
+            HostPattern::Subdomain(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+            HostPattern::Regex(regex) => regex.is_match(&host),
+        };
+        host_matches
+            && match (&self.path_prefix, path) {
+                (Some(prefix), Some(path)) => has_path_prefix(path, prefix),
+                _ => true,
+            }
+    }
+}
+
+// Whole path segments only: "/videos" covers "/videos" and "/videos/1", not "/videos2"
+fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+// Lowercase host without port or trailing dot
+fn normalize_host(authority: &str) -> String {
+    let host = match authority.rsplit_once(':') {
+        // Bracketed IPv6 literals keep their colons
+        Some((host, port)) if !host.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => authority,
+    };
+    host.trim_end_matches('.').to_lowercase()
+}
+
+// Host and path of a URL, with or without its scheme, or just the host when
+// given a bare hostname
+pub fn split_target(target: &str) -> (String, Option<String>) {
+    let (has_scheme, rest) = match target.split_once("://") {
+        Some((_, rest)) => (true, rest),
+        None => (false, target),
+    };
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], Some(rest[slash..].to_string())),
+        None => (rest, has_scheme.then(|| "/".to_string())),
+    };
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    (host.to_string(), path)
+}
+
+// A site entry under [websites]: either `vimeo = true` or a `[websites.vimeo]`
+// table with its own settings
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum SiteSetting {
+    Enabled(bool),
+    Profile(SiteProfile),
+}
+
+impl SiteSetting {
+    pub fn enabled(&self) -> bool {
+        match self {
+            SiteSetting::Enabled(enabled) => *enabled,
+            SiteSetting::Profile(profile) => profile.enabled.unwrap_or(true),
+        }
+    }
+
+    pub fn profile(&self) -> Option<&SiteProfile> {
+        match self {
+            SiteSetting::Enabled(_) => None,
+            SiteSetting::Profile(profile) => Some(profile),
+        }
+    }
+}
+
+// Per-site overrides of the [proxy] chunking, prefetch and retry settings;
+// unset fields keep the global value
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteProfile {
+    pub enabled: Option<bool>,
+    #[serde(default, deserialize_with = "crate::config::deserialize_optional_size")]
+    pub chunk_size: Option<u64>,
+    #[serde(default, deserialize_with = "crate::config::deserialize_optional_size")]
+    pub prefetch_ahead: Option<u64>,
+    pub max_concurrent_chunks: Option<u32>,
+    pub retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+}
+
+// Effective chunking, prefetch and retry settings for requests to one site
+#[derive(Debug, Clone)]
+pub struct ChunkPolicy {
+    pub site: String,
+    pub chunk_size: u64,
+    pub prefetch_ahead: u64,
+    pub max_concurrent_chunks: u32,
+    pub retry: RetryPolicy,
+}
+
+impl ChunkPolicy {
+    // This policy with the profile's overrides applied
+    pub fn with_profile(&self, site: &str, profile: &SiteProfile) -> Self {
+        Self {
+            site: site.to_string(),
+            chunk_size: profile.chunk_size.unwrap_or(self.chunk_size),
+            prefetch_ahead: profile.prefetch_ahead.unwrap_or(self.prefetch_ahead),
+            max_concurrent_chunks: profile.max_concurrent_chunks.unwrap_or(self.max_concurrent_chunks),
+            retry: RetryPolicy {
+                attempts: profile.retries.unwrap_or(self.retry.attempts),
+                backoff: profile
+                    .retry_backoff_ms
+                    .map_or(self.retry.backoff, Duration::from_millis),
+            },
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "chunk {} bytes, prefetch {} bytes, max {} concurrent, {} retries",
+            self.chunk_size, self.prefetch_ahead, self.max_concurrent_chunks, self.retry.attempts
+        )
+    }
+}
+
+// Enabled sites with their policies. Decides which hosts are handled and
+// which policy a request gets; the first matching site wins.
+#[derive(Debug)]
+pub struct SitePolicies {
+    default: Arc<ChunkPolicy>,
+    sites: Vec<(Vec<SitePattern>, Arc<ChunkPolicy>)>,
+}
+
+impl SitePolicies {
+    pub fn new(default: ChunkPolicy) -> Self {
+        Self {
+            default: Arc::new(default),
+            sites: Vec::new(),
+        }
+    }
+
+    pub fn default_policy(&self) -> &ChunkPolicy {
+        &self.default
+    }
+
+    pub fn add(&mut self, patterns: Vec<SitePattern>, policy: ChunkPolicy) {
+        self.sites.push((patterns, Arc::new(policy)));
+    }
+
+    fn find(&self, host: &str, path: Option<&str>) -> Option<&Arc<ChunkPolicy>> {
+        self.sites
+            .iter()
+            .find(|(patterns, _)| patterns.iter().any(|pattern| pattern.matches(host, path)))
+            .map(|(_, policy)| policy)
+    }
+
+    pub fn matches(&self, host: &str, path: Option<&str>) -> bool {
+        self.find(host, path).is_some()
+    }
+
+    // Policy of the matching site; None when no enabled site matches
+    pub fn for_request(&self, host: &str, path: Option<&str>) -> Option<Arc<ChunkPolicy>> {
+        self.find(host, path).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(site: &str) -> ChunkPolicy {
+        ChunkPolicy {
+            site: site.to_string(),
+            chunk_size: 1024,
+            prefetch_ahead: 0,
+            max_concurrent_chunks: 1,
+            retry: RetryPolicy {
+                attempts: 0,
+                backoff: Duration::ZERO,
+            },
+        }
+    }
+
+    #[test]
+    fn host_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("example.com", "example.com", true),
+            ("example.com", "EXAMPLE.com.", true),
+            ("example.com", "example.com:8443", true),
+            ("example.com", "www.example.com", false),
+            ("example.com", "badexample.com", false),
+            ("https://Example.com", "example.com", true),
+            ("*.example.com", "www.example.com", true),
+            ("*.example.com", "a.b.example.com", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", "badexample.com", false),
+            ("*.example.com", "example.com.evil.net", false),
+            ("[::1]", "[::1]:443", true),
+            // Regexes are not anchored unless they say so
+            ("re:example\\.com", "cdn.example.com.evil.net", true),
+            ("re:^cdn[0-9]+\\.example\\.com$", "cdn12.example.com", true),
+            ("re:^cdn[0-9]+\\.example\\.com$", "cdn12.example.com.evil.net", false),
+            ("re:^cdn[0-9]+\\.example\\.com$", "xcdn1.example.com", false),
+            ("re:^cdn[0-9]+\\.example\\.com$", "CDN1.Example.com", true),
+        ];
+        for (pattern, host, expected) in cases {
+            let parsed = SitePattern::parse(pattern).unwrap();
+            assert_eq!(parsed.matches(host, Some("/")), *expected, "{} vs {}", pattern, host);
+        }
+    }
+
+    #[test]
+    fn path_prefixes() {
+        let cases: &[(&str, &str, Option<&str>, bool)] = &[
+            ("example.com/videos", "example.com", Some("/videos"), true),
+            ("example.com/videos", "example.com", Some("/videos/1.mp4"), true),
+            ("example.com/videos", "example.com", Some("/videos2/1.mp4"), false),
+            ("example.com/videos", "example.com", Some("/video"), false),
+            ("example.com/videos/", "example.com", Some("/videos/1.mp4"), true),
+            ("example.com/videos/", "example.com", Some("/videos"), false),
+            ("example.com/videos/", "example.com", Some("/other/videos/1.mp4"), false),
+            ("example.com/videos/", "www.example.com", Some("/videos/1.mp4"), false),
+            // Only the host is known for CONNECT
+            ("example.com/videos/", "example.com", None, true),
+            ("*.example.com/live/", "cdn.example.com", Some("/live/seg1.ts"), true),
+            ("*.example.com/live/", "cdn.example.com", Some("/vod/seg1.ts"), false),
+        ];
+        for (pattern, host, path, expected) in cases {
+            let parsed = SitePattern::parse(pattern).unwrap();
+            assert_eq!(parsed.matches(host, *path), *expected, "{} vs {}{:?}", pattern, host, path);
+        }
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        let cases: &[(&str, &str)] = &[
+            ("re:(unclosed", "Invalid regex"),
+            ("re:[z-a]", "Invalid regex"),
+            ("", "no host"),
+            ("*.", "use '*.domain'"),
+            ("https:///videos", "no host"),
+            ("*example.com", "use '*.domain'"),
+            ("*", "use '*.domain'"),
+        ];
+        for (pattern, error) in cases {
+            let e = SitePattern::parse(pattern).unwrap_err();
+            assert!(e.contains(error), "{:?}: {}", pattern, e);
+        }
+    }
+
+    #[test]
+    fn first_matching_site_wins() {
+        let patterns = |list: &[&str]| list.iter().map(|p| SitePattern::parse(p).unwrap()).collect();
+        let mut sites = SitePolicies::new(policy("default"));
+        sites.add(patterns(&["example.com/live/"]), policy("live"));
+        sites.add(patterns(&["example.com", "*.example.com"]), policy("example"));
+        sites.add(patterns(&["re:example"]), policy("regex"));
+
+        let site = |host: &str, path: Option<&str>| sites.for_request(host, path).map(|policy| policy.site.clone());
+        assert_eq!(site("example.com", Some("/live/1.ts")), Some("live".to_string()));
+        assert_eq!(site("example.com", Some("/vod/1.ts")), Some("example".to_string()));
+        assert_eq!(site("cdn.example.com", Some("/live/1.ts")), Some("example".to_string()));
+        assert_eq!(site("example.net", Some("/")), Some("regex".to_string()));
+        assert_eq!(site("other.net", Some("/")), None);
+        assert!(sites.matches("example.com", None));
+        assert!(!sites.matches("other.net", None));
+    }
+
+    #[test]
+    fn targets() {
+        let cases: &[(&str, &str, Option<&str>)] = &[
+            ("https://youtube.com/watch?v=abc", "youtube.com", Some("/watch")),
+            ("https://user@example.com:8443", "example.com:8443", Some("/")),
+            ("youtube.com/watch?v=abc", "youtube.com", Some("/watch")),
+            ("example.com/videos/1#t=10", "example.com", Some("/videos/1")),
+            ("example.com", "example.com", None),
+            ("example.com:443", "example.com:443", None),
+        ];
+        for (target, host, path) in cases {
+            assert_eq!(split_target(target), (host.to_string(), path.map(str::to_string)), "{}", target);
+        }
+
+        let mut sites = SitePolicies::new(policy("default"));
+        sites.add(vec![SitePattern::parse("example.com/videos/").unwrap()], policy("videos"));
+        let (host, path) = split_target("example.com/live/1");
+        assert!(!sites.matches(&host, path.as_deref()));
+        let (host, path) = split_target("example.com/videos/1");
+        assert!(sites.matches(&host, path.as_deref()));
+    }
+}
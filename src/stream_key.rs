@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use hyper::Uri;
+
+// Query parameters that identify the media on hosts whose URLs also carry
+// volatile parameters (request numbers, buffer hints, signatures)
+const BUILTIN_RULES: &[(&str, &[&str])] = &[("googlevideo.com", &["id", "itag", "clen"])];
+
+// Derives a stable key for the media a request refers to, so requests for
+// the same stream with different volatile parameters are recognized as one
+#[derive(Debug, Clone)]
+pub struct StreamKeyRules {
+    // (domain, identifying params), most specific domain first
+    rules: Vec<(String, Vec<String>)>,
+}
+
+impl Default for StreamKeyRules {
+    fn default() -> Self {
+        Self::new(&HashMap::new())
+    }
+}
+
+impl StreamKeyRules {
+    // Configured rules take precedence over built-in ones for the same domain
+    pub fn new(custom: &HashMap<String, Vec<String>>) -> Self {
+        let mut rules: HashMap<String, Vec<String>> = BUILTIN_RULES
+            .iter()
+            .map(|(domain, params)| {
+                (domain.to_string(), params.iter().map(|p| p.to_string()).collect())
+            })
+            .collect();
+        for (domain, params) in custom {
+            rules.insert(domain.trim_start_matches("*.").to_lowercase(), params.clone());
+        }
+
+        let mut rules: Vec<(String, Vec<String>)> = rules.into_iter().collect();
+        rules.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        Self { rules }
+    }
+
+    // "domain/path?param=value&..." when the host has a rule and every identifying
+    // parameter is present; the full URI otherwise
+    pub fn key_for(&self, uri: &Uri) -> String {
+        let host = match uri.host() {
+            Some(host) => host.to_lowercase(),
+            None => return uri.to_string(),
+        };
+
+        let rule = self.rules.iter().find(|(domain, _)| {
+            host == *domain || host.ends_with(&format!(".{}", domain))
+        });
+        let (domain, params) = match rule {
+            Some(rule) => rule,
+            None => return uri.to_string(),
+        };
+
+        let query: Vec<(&str, &str)> = uri
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect();
+
+        let mut identity = Vec::with_capacity(params.len());
+        for param in params {
+            match query.iter().find(|(name, _)| name == param) {
+                Some((name, value)) => identity.push(format!("{}={}", name, value)),
+                None => return uri.to_string(),
+            }
+        }
+
+        format!("{}{}?{}", domain, uri.path(), identity.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(rules: &StreamKeyRules, url: &str) -> String {
+        rules.key_for(&url.parse().unwrap())
+    }
+
+    #[test]
+    fn googlevideo_volatile_params_are_ignored() {
+        let rules = StreamKeyRules::default();
+        let first = key(
+            &rules,
+            "https://rr1---sn-a5mekn6s.googlevideo.com/videoplayback?expire=1700000000&id=o-AB12&itag=248\
+             &source=youtube&clen=52428800&rn=1&range=0-1048575&sig=AOq0QJ8wRQIg&lsig=AG3C_x",
+        );
+        // Another node, request number, range, expiry and signature
+        let second = key(
+            &rules,
+            "https://rr3---sn-n4v7sney.googlevideo.com/videoplayback?expire=1700003600&itag=248&id=o-AB12\
+             &clen=52428800&rn=7&range=1048576-2097151&sig=BXr1RK9xSRJh&lsig=BH4D_y&rbuf=4096",
+        );
+        assert_eq!(first, second);
+        assert_eq!(first, "googlevideo.com/videoplayback?id=o-AB12&itag=248&clen=52428800");
+
+        // The audio stream of the same video is a different stream
+        let audio = key(
+            &rules,
+            "https://rr1---sn-a5mekn6s.googlevideo.com/videoplayback?expire=1700000000&id=o-AB12&itag=251\
+             &clen=3145728&rn=2&range=0-1048575&sig=AOq0QJ8wRQIg",
+        );
+        assert_ne!(first, audio);
+    }
+
+    #[test]
+    fn full_uri_without_a_rule_or_identifying_params() {
+        let rules = StreamKeyRules::default();
+        for url in [
+            "https://video.example.com/clip.mp4?range=0-1023",
+            // clen is missing
+            "https://rr1---sn-a5mekn6s.googlevideo.com/videoplayback?id=o-AB12&itag=248&range=0-1023",
+            // Only a suffix of the label, not a subdomain
+            "https://notgooglevideo.com/videoplayback?id=o-AB12&itag=248&clen=1",
+        ] {
+            assert_eq!(key(&rules, url), url);
+        }
+    }
+
+    #[test]
+    fn configured_rules() {
+        let custom = HashMap::from([
+            ("*.cdn.example.com".to_string(), vec!["file".to_string()]),
+            ("example.com".to_string(), vec!["id".to_string()]),
+            ("googlevideo.com".to_string(), vec!["id".to_string(), "itag".to_string()]),
+        ]);
+        let rules = StreamKeyRules::new(&custom);
+
+        // The most specific domain wins
+        assert_eq!(
+            key(&rules, "https://a.cdn.example.com/v?file=x.mp4&token=1&id=2"),
+            "cdn.example.com/v?file=x.mp4"
+        );
+        assert_eq!(key(&rules, "https://www.example.com/v?id=2&token=1"), "example.com/v?id=2");
+        // Replaces the built-in rule
+        assert_eq!(
+            key(&rules, "https://rr1---sn-a5mekn6s.googlevideo.com/videoplayback?id=o-AB12&itag=248&rn=1"),
+            "googlevideo.com/videoplayback?id=o-AB12&itag=248"
+        );
+    }
+}
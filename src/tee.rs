@@ -0,0 +1,425 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use futures_util::stream;
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE};
+use hyper::http::{HeaderMap, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::resource::{parse_content_range, ResourceInfo};
+
+// How often the sidecar of a stream that is still being written is updated
+const SIDECAR_INTERVAL: Duration = Duration::from_secs(1);
+
+// Pieces waiting for the writer; more are dropped rather than held in memory
+// while the disk falls behind
+const QUEUED_PIECES: usize = 256;
+
+// What is known about a stream from the response headers, shared by all the
+// pieces of one response
+#[derive(Debug)]
+struct StreamInfo {
+    key: String,
+    mime: Option<String>,
+    length: Option<u64>,
+}
+
+// Media bytes of one stream at `offset`
+#[derive(Debug)]
+struct Piece {
+    stream: Arc<StreamInfo>,
+    offset: u64,
+    data: Bytes,
+}
+
+// Saves the media that passes through the proxy: the bytes of each stream are
+// written into a sparse file at their offset, and a sidecar lists the byte
+// ranges already on disk so a later session can fill in the rest. Disk writes
+// happen on a separate thread so the proxy never waits for them.
+#[derive(Debug)]
+pub struct Tee {
+    dir: PathBuf,
+    pieces: SyncSender<Piece>,
+    // Bytes of all streams skipped while the writer was behind
+    dropped: Arc<AtomicU64>,
+}
+
+impl Tee {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let (pieces, received) = mpsc::sync_channel(QUEUED_PIECES);
+        let writer_dir = dir.clone();
+        std::thread::Builder::new()
+            .name("tee-writer".to_string())
+            .spawn(move || write_pieces(&writer_dir, received))?;
+        Ok(Self {
+            dir,
+            pieces,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Copier for the body of a response for stream `key`, if it is media
+    pub fn stream(&self, key: &str, status: StatusCode, headers: &HeaderMap) -> Option<BodyCopy> {
+        let header = |name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+        let (offset, length) = match status {
+            StatusCode::PARTIAL_CONTENT => header(CONTENT_RANGE)
+                .and_then(parse_content_range)
+                .map(|(start, _, length)| (start, length))?,
+            StatusCode::OK => (0, header(CONTENT_LENGTH).and_then(|value| value.parse().ok())),
+            _ => return None,
+        };
+        let mime = header(CONTENT_TYPE).map(|value| value.split(';').next().unwrap_or(value).trim().to_string());
+        let resource = ResourceInfo {
+            length,
+            mime: mime.clone(),
+        };
+        // Unknown types are chunked as media, but only known media is saved
+        if mime.is_none() || !resource.is_media() {
+            return None;
+        }
+
+        Some(BodyCopy {
+            pieces: self.pieces.clone(),
+            stream: Arc::new(StreamInfo {
+                key: key.to_string(),
+                mime,
+                length,
+            }),
+            offset,
+            dropped: 0,
+            total_dropped: self.dropped.clone(),
+        })
+    }
+
+    // Copy the body of a response passed straight through to the player as
+    // it is read
+    pub fn observe(&self, key: &str, response: Response<Body>) -> Response<Body> {
+        let Some(copy) = self.stream(key, response.status(), response.headers()) else {
+            return response;
+        };
+        let (parts, body) = response.into_parts();
+        let copied = stream::unfold((body, copy), |(mut body, mut copy)| async move {
+            let data = body.data().await?;
+            if let Ok(bytes) = &data {
+                copy.send(bytes);
+            }
+            Some((data, (body, copy)))
+        });
+        Response::from_parts(parts, Body::wrap_stream(copied))
+    }
+}
+
+// Sends the body of one response to the writer, piece by piece. Pieces the
+// writer has no room for are skipped; their bytes are left out of the sidecar
+// for a later session to fill in.
+#[derive(Debug)]
+pub struct BodyCopy {
+    pieces: SyncSender<Piece>,
+    stream: Arc<StreamInfo>,
+    offset: u64,
+    // Bytes skipped while the writer was behind
+    dropped: u64,
+    total_dropped: Arc<AtomicU64>,
+}
+
+impl BodyCopy {
+    pub fn send(&mut self, data: &Bytes) {
+        let piece = Piece {
+            stream: self.stream.clone(),
+            offset: self.offset,
+            data: data.clone(),
+        };
+        if let Err(TrySendError::Full(_)) = self.pieces.try_send(piece) {
+            self.dropped += data.len() as u64;
+            self.total_dropped.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        self.offset += data.len() as u64;
+    }
+}
+
+impl Drop for BodyCopy {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            eprintln!(
+                "Warning: Disk writes fell behind, {} bytes of {} not saved",
+                self.dropped, self.stream.key
+            );
+        }
+    }
+}
+
+// Byte ranges of a stream that are on disk, for resuming a partial file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Sidecar {
+    stream: String,
+    length: Option<u64>,
+    // Sorted, non-overlapping [start, end) ranges
+    complete: Vec<(u64, u64)>,
+}
+
+impl Sidecar {
+    fn add(&mut self, start: u64, end: u64) {
+        let (mut start, mut end) = (start, end);
+        self.complete.retain(|&(s, e)| {
+            let touches = s <= end && start <= e;
+            if touches {
+                start = start.min(s);
+                end = end.max(e);
+            }
+            !touches
+        });
+        let at = self.complete.partition_point(|&(s, _)| s < start);
+        self.complete.insert(at, (start, end));
+    }
+
+    fn is_complete(&self) -> bool {
+        self.length.is_some_and(|length| self.complete == [(0, length)])
+    }
+}
+
+// A stream file being filled in
+#[derive(Debug)]
+struct SavedStream {
+    file: File,
+    part: PathBuf,
+    sidecar_path: PathBuf,
+    finished: PathBuf,
+    sidecar: Sidecar,
+    // Sidecar differs from what is on disk
+    dirty: bool,
+    saved_at: Instant,
+}
+
+impl SavedStream {
+    fn open(dir: &Path, key: &str, mime: Option<&str>) -> io::Result<Option<Self>> {
+        let name = file_name(key, mime);
+        let finished = dir.join(&name);
+        if finished.exists() {
+            eprintln!("Stream already saved: {}", finished.display());
+            return Ok(None);
+        }
+
+        let part = dir.join(format!("{}.part", name));
+        let sidecar_path = dir.join(format!("{}.part.ranges", name));
+        // Resume what an earlier session saved, if the file is still there
+        let sidecar = fs::read(&sidecar_path)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<Sidecar>(&json).ok())
+            .filter(|sidecar| sidecar.stream == key && part.exists())
+            .unwrap_or_else(|| Sidecar {
+                stream: key.to_string(),
+                ..Sidecar::default()
+            });
+        if !sidecar.complete.is_empty() {
+            eprintln!("Resuming saved stream: {} ({} ranges on disk)", part.display(), sidecar.complete.len());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(sidecar.complete.is_empty())
+            .open(&part)?;
+
+        Ok(Some(Self {
+            file,
+            part,
+            sidecar_path,
+            finished,
+            sidecar,
+            dirty: true,
+            saved_at: Instant::now(),
+        }))
+    }
+
+    fn write(&mut self, piece: &Piece) -> io::Result<()> {
+        if self.sidecar.length.is_none() {
+            if let Some(length) = piece.stream.length {
+                // Sized up front, the file stays sparse until it is filled in
+                self.file.set_len(length)?;
+                self.sidecar.length = Some(length);
+            }
+        }
+        self.file.seek(SeekFrom::Start(piece.offset))?;
+        self.file.write_all(&piece.data)?;
+        self.sidecar.add(piece.offset, piece.offset + piece.data.len() as u64);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn save_sidecar(&mut self) -> io::Result<()> {
+        fs::write(&self.sidecar_path, serde_json::to_vec(&self.sidecar)?)?;
+        self.dirty = false;
+        self.saved_at = Instant::now();
+        Ok(())
+    }
+
+    // Rename the file to its final name once every byte is on disk
+    fn finish(&mut self) -> io::Result<bool> {
+        if !self.sidecar.is_complete() {
+            return Ok(false);
+        }
+        self.file.sync_all()?;
+        fs::rename(&self.part, &self.finished)?;
+        let _ = fs::remove_file(&self.sidecar_path);
+        eprintln!(
+            "Stream saved: {} ({} bytes)",
+            self.finished.display(),
+            self.sidecar.length.unwrap_or(0)
+        );
+        Ok(true)
+    }
+}
+
+// Streams that are saved, already on disk or failed are skipped from then on
+fn write_pieces(dir: &Path, pieces: Receiver<Piece>) {
+    let mut streams: HashMap<String, Option<SavedStream>> = HashMap::new();
+    loop {
+        let mut touched = HashSet::new();
+        match pieces.recv_timeout(SIDECAR_INTERVAL) {
+            Ok(piece) => {
+                for piece in std::iter::once(piece).chain(pieces.try_iter()) {
+                    let info = &piece.stream;
+                    if !streams.contains_key(&info.key) {
+                        let opened = SavedStream::open(dir, &info.key, info.mime.as_deref()).unwrap_or_else(|e| {
+                            eprintln!("Warning: Cannot save stream {}: {}", info.key, e);
+                            None
+                        });
+                        streams.insert(info.key.clone(), opened);
+                    }
+                    let stream = streams.get_mut(&info.key).unwrap();
+                    if let Some(saved) = stream {
+                        if let Err(e) = saved.write(&piece) {
+                            eprintln!("Warning: Failed to save {}: {}", saved.part.display(), e);
+                            *stream = None;
+                            continue;
+                        }
+                        touched.insert(info.key.clone());
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                for saved in streams.values_mut().flatten().filter(|saved| saved.dirty) {
+                    let _ = saved.save_sidecar();
+                }
+                return;
+            }
+        }
+
+        for (key, stream) in streams.iter_mut() {
+            let Some(saved) = stream else {
+                continue;
+            };
+            let result = if touched.contains(key) {
+                saved.finish()
+            } else {
+                Ok(false)
+            };
+            let result = match result {
+                Ok(true) => {
+                    *stream = None;
+                    continue;
+                }
+                Ok(false) if saved.dirty && saved.saved_at.elapsed() >= SIDECAR_INTERVAL => saved.save_sidecar(),
+                Ok(false) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Warning: Failed to save {}: {}", saved.part.display(), e);
+                *stream = None;
+            }
+        }
+    }
+}
+
+// Readable and stable across sessions: the last path segment, the googlevideo
+// id and itag when present, and a hash of the stream key
+fn file_name(key: &str, mime: Option<&str>) -> String {
+    let without_scheme = key.split_once("://").map_or(key, |(_, rest)| rest);
+    let (path, query) = without_scheme.split_once('?').unwrap_or((without_scheme, ""));
+    let segment = path.rsplit('/').next().filter(|segment| !segment.is_empty()).unwrap_or("stream");
+    // An extension from the URL is only kept when it is safe in a file name
+    let usable = |extension: &str| !extension.is_empty() && extension.len() <= 8 && extension.chars().all(is_name_char);
+    let (stem, extension) = match segment.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && usable(extension) => (stem, Some(extension)),
+        _ => (segment, None),
+    };
+
+    let mut name = stem.to_string();
+    for param in ["id", "itag"] {
+        let value = query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(param)?.strip_prefix('='));
+        if let Some(value) = value {
+            name.push_str(&format!("-{}{}", param, value));
+        }
+    }
+    let mut name: String = name
+        .chars()
+        .map(|c| if is_name_char(c) { c } else { '_' })
+        .take(80)
+        .collect();
+    name.push_str(&format!("-{:08x}", fnv1a(key) as u32));
+
+    let extension = extension.map(str::to_string).unwrap_or_else(|| {
+        match mime.unwrap_or_default() {
+            "video/mp4" => "mp4",
+            "audio/mp4" => "m4a",
+            "video/webm" => "webm",
+            "audio/webm" => "weba",
+            _ => "bin",
+        }
+        .to_string()
+    });
+    format!("{}.{}", name, extension)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+// FNV-1a; unlike DefaultHasher it is stable across Rust versions, so file
+// names stay the same for the next session
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names() {
+        let hash = |key: &str| format!("{:08x}", fnv1a(key) as u32);
+        let key = "https://cdn.test/videoplayback?id=abc&itag=22";
+        assert_eq!(file_name(key, Some("video/mp4")), format!("videoplayback-idabc-itag22-{}.mp4", hash(key)));
+        let key = "https://cdn.test/clip.webm";
+        assert_eq!(file_name(key, Some("video/mp4")), format!("clip-{}.webm", hash(key)));
+        // Unsafe extensions are replaced by the one for the MIME type
+        for key in ["https://cdn.test/clip.m%2F..", "https://cdn.test/clip.mp4 x", "https://cdn.test/clip.verylongext"] {
+            let name = file_name(key, Some("audio/webm"));
+            assert!(name.ends_with(&format!("-{}.weba", hash(key))), "{}", name);
+            assert!(name.chars().all(|c| is_name_char(c) || c == '.'), "{}", name);
+            assert_eq!(name.matches('.').count(), 1, "{}", name);
+        }
+    }
+}
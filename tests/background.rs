@@ -0,0 +1,47 @@
+// Background download of whole streams
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{Origin, PlayerClient, ProxyProcess, TestCa, DATA_LEN, DATA_PATH};
+use hyper::StatusCode;
+
+const CHUNK: u64 = 128 * 1024;
+
+// Like ProxyProcess::wait_for_log, but lets the in-process origin keep serving
+async fn wait_logged(proxy: &ProxyProcess, expected: &str) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !proxy.logs().iter().any(|line| line.contains(expected)) {
+        assert!(Instant::now() < deadline, "no log line containing {:?}:\n{}", expected, proxy.logs().join("\n"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn whole_stream_is_fetched_and_seeks_are_served_from_it() {
+    let ca = TestCa::generate();
+    let origin = Origin::start(&ca, None).await;
+    let config = "[background]\nenabled = true\nidle_timeout = 2\n";
+    let proxy = ProxyProcess::start(&ca, CHUNK, config);
+    let mut client = PlayerClient::connect(proxy.addr, &origin, &ca).await;
+    let data = common::test_data();
+
+    // The length is learned from the first response; the second starts the download
+    for start in [0, CHUNK] {
+        let response = client.get(DATA_PATH, Some(&format!("bytes={}-", start))).await;
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    }
+    wait_logged(&proxy, " complete: ").await;
+    let fetched = origin.ranges(DATA_PATH).len();
+    assert_eq!(fetched as u64, (DATA_LEN as u64).div_ceil(CHUNK));
+
+    // A seek near the end needs no further upstream request
+    let seek = DATA_LEN as u64 - 1000;
+    let response = client.get(DATA_PATH, Some(&format!("bytes={}-", seek))).await;
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.body, data[seek as usize..]);
+    assert_eq!(origin.ranges(DATA_PATH).len(), fetched);
+
+    wait_logged(&proxy, "stopped: idle for 2s").await;
+}
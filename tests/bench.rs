@@ -0,0 +1,41 @@
+// Chunk size benchmark against the local test origin
+
+use http_ytproxy::bench::{self, Bench, LocalOrigin};
+use http_ytproxy::connector::Connector;
+use http_ytproxy::proxy::build_client;
+
+const BYTES: u64 = 4 * 1024 * 1024;
+
+#[tokio::test]
+async fn matrix_reports_throttling_and_recommends_a_clean_setting() {
+    let origin = LocalOrigin::start().await.unwrap();
+    let client = build_client(Connector::new(), 8).unwrap();
+    let target = Bench::probe(client, origin.url()).await.unwrap();
+    assert!(target.length() > BYTES);
+
+    let mut results = Vec::new();
+    for chunk_size in [256 * 1024, 1024 * 1024] {
+        for concurrency in [1, 8] {
+            results.push(target.run(chunk_size, concurrency, BYTES).await);
+        }
+    }
+
+    for result in &results {
+        assert_eq!(result.requests as u64, BYTES.div_ceil(result.chunk_size));
+        assert_eq!(result.failed, 0);
+        assert!(result.ttfb(50.0).is_some());
+    }
+    // More concurrent requests than the origin allows are refused
+    let crowded = &results[1];
+    assert!(crowded.throttled > 0);
+    assert!(crowded.bytes < BYTES);
+    assert!(results[0].is_clean());
+    assert_eq!(results[0].bytes, BYTES);
+
+    let best = bench::recommend(&results).expect("no clean setting");
+    assert!(best.is_clean());
+    assert!(results
+        .iter()
+        .filter(|result| result.is_clean())
+        .all(|result| result.throughput() <= best.throughput() * 1.05));
+}
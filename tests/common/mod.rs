@@ -0,0 +1,424 @@
+// Shared harness for the integration tests: a throwaway CA, a local HTTPS
+// origin serving a known file, the proxy binary and an mpv-like client that
+// talks to the origin through the proxy's CONNECT tunnel. Everything stays on
+// 127.0.0.1, so the tests run offline.
+#![allow(dead_code)]
+
+use std::convert::Infallible;
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_ytproxy::resource::parse_range;
+use hyper::client::conn::SendRequest;
+use hyper::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::symm::Cipher;
+use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectKeyIdentifier};
+use openssl::x509::{X509NameBuilder, X509};
+use tempfile::TempDir;
+use third_wheel::{create_signed_certificate_for_domain, CertificateAuthority};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub const ORIGIN_HOST: &str = "video.test";
+pub const DATA_PATH: &str = "/data.bin";
+pub const PAGE_PATH: &str = "/page.html";
+pub const DATA_LEN: usize = 1_000_003;
+const PASSPHRASE: &str = "third-wheel";
+
+// Deterministic, non-repeating-looking content so misplaced bytes show up
+pub fn test_data() -> Vec<u8> {
+    (0..DATA_LEN)
+        .map(|i| (i ^ (i >> 8) ^ (i >> 16)).wrapping_mul(31) as u8)
+        .collect()
+}
+
+// Self-signed CA written to a temp directory, in the files the proxy loads
+pub struct TestCa {
+    pub cert: X509,
+    pub key: PKey<Private>,
+    pub dir: TempDir,
+}
+
+impl TestCa {
+    pub fn generate() -> Self {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "http-ytproxy test CA").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder
+            .append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build().unwrap())
+            .unwrap();
+        let key_id = SubjectKeyIdentifier::new()
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(key_id).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ca.pem"), cert.to_pem().unwrap()).unwrap();
+        let encrypted_key = key
+            .rsa()
+            .unwrap()
+            .private_key_to_pem_passphrase(Cipher::aes_256_cbc(), PASSPHRASE.as_bytes())
+            .unwrap();
+        std::fs::write(dir.path().join("ca-key.pem"), encrypted_key).unwrap();
+
+        Self { cert, key, dir }
+    }
+
+    pub fn cert_file(&self) -> PathBuf {
+        self.dir.path().join("ca.pem")
+    }
+
+    pub fn key_file(&self) -> PathBuf {
+        self.dir.path().join("ca-key.pem")
+    }
+
+    pub fn authority(&self) -> CertificateAuthority {
+        CertificateAuthority {
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+        }
+    }
+
+    // TLS identity for `host`, signed by this CA
+    fn identity(&self, host: &str) -> native_tls::Identity {
+        let cert = create_signed_certificate_for_domain(host, &self.authority()).unwrap();
+        native_tls::Identity::from_pkcs8(
+            &cert.to_pem().unwrap(),
+            &self.key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap()
+    }
+}
+
+// Upstream pacing: `bytes` are written, then the origin sleeps for `delay`
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    pub bytes: usize,
+    pub delay: Duration,
+}
+
+// (path, Range header) of every request the origin received
+type RequestLog = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+// HTTPS origin for ORIGIN_HOST serving test_data() at DATA_PATH with byte
+// range support, and a small HTML page at PAGE_PATH
+pub struct Origin {
+    pub addr: SocketAddr,
+    requests: RequestLog,
+}
+
+impl Origin {
+    pub async fn start(ca: &TestCa, throttle: Option<Throttle>) -> Self {
+        let acceptor = tokio_native_tls::TlsAcceptor::from(
+            native_tls::TlsAcceptor::new(ca.identity(ORIGIN_HOST)).unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = RequestLog::default();
+        let data = Bytes::from(test_data());
+
+        let log = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let acceptor = acceptor.clone();
+                let log = log.clone();
+                let data = data.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let service = service_fn(move |req: Request<Body>| {
+                        let range = req
+                            .headers()
+                            .get(RANGE)
+                            .and_then(|v| v.to_str().ok())
+                            .map(str::to_string);
+                        log.lock().unwrap().push((req.uri().path().to_string(), range.clone()));
+                        let response = serve(req.uri().path(), range.as_deref(), &data, throttle);
+                        async move { Ok::<_, Infallible>(response) }
+                    });
+                    let _ = Http::new().serve_connection(stream, service).await;
+                });
+            }
+        });
+
+        Self { addr, requests }
+    }
+
+    // Range headers received for `path`, in arrival order (None = no Range)
+    pub fn ranges(&self, path: &str) -> Vec<Option<String>> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(p, _)| p == path)
+            .map(|(_, range)| range.clone())
+            .collect()
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("https://{}:{}{}", ORIGIN_HOST, self.addr.port(), path)
+    }
+}
+
+fn serve(path: &str, range: Option<&str>, data: &Bytes, throttle: Option<Throttle>) -> Response<Body> {
+    let (content, mime) = match path {
+        DATA_PATH => (data.clone(), "video/mp4"),
+        PAGE_PATH => (Bytes::from_static(b"<html><body>not a video</body></html>"), "text/html"),
+        _ => return status(StatusCode::NOT_FOUND),
+    };
+    let len = content.len() as u64;
+
+    let Some(range) = range else {
+        return Response::builder()
+            .header(CONTENT_TYPE, mime)
+            .header(CONTENT_LENGTH, len)
+            .body(paced(content, throttle))
+            .unwrap();
+    };
+
+    let (start, end) = match parse_range(range) {
+        Some((start, end)) if start < len => (start, end.unwrap_or(len - 1).min(len - 1)),
+        _ => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty())
+                .unwrap();
+        }
+    };
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(CONTENT_TYPE, mime)
+        .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))
+        .header(CONTENT_LENGTH, end - start + 1)
+        .body(paced(content.slice(start as usize..=end as usize), throttle))
+        .unwrap()
+}
+
+fn paced(content: Bytes, throttle: Option<Throttle>) -> Body {
+    let Some(throttle) = throttle else {
+        return Body::from(content);
+    };
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for piece in content.chunks(throttle.bytes) {
+            if sender.send_data(content.slice_ref(piece)).await.is_err() {
+                return;
+            }
+            tokio::time::sleep(throttle.delay).await;
+        }
+    });
+    body
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+// The http-ytproxy binary, configured for the test origin and listening on a
+// free port. Its stderr is collected so tests can check the logged rewrites.
+pub struct ProxyProcess {
+    pub addr: SocketAddr,
+    child: Child,
+    logs: Arc<Mutex<Vec<String>>>,
+    _config_dir: TempDir,
+}
+
+impl ProxyProcess {
+    // `extra_config` is appended to a config that routes ORIGIN_HOST to the
+    // local origin and enables it as a custom domain
+    pub fn start(ca: &TestCa, chunk_size: u64, extra_config: &str) -> Self {
+        let config_dir = tempfile::tempdir().unwrap();
+        let config_path = config_dir.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "[websites]\ncustom_domains = [\"{host}\"]\n\n[dns.hosts]\n\"{host}\" = \"127.0.0.1\"\n\n{extra}\n",
+                host = ORIGIN_HOST,
+                extra = extra_config
+            ),
+        )
+        .unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_http-ytproxy"))
+            .arg("--config")
+            .arg(&config_path)
+            .arg("-p")
+            .arg("0")
+            .arg("-c")
+            .arg(ca.cert_file())
+            .arg("-k")
+            .arg(ca.key_file())
+            .arg("-r")
+            .arg(chunk_size.to_string())
+            // Upstream TLS trusts the test CA, which signed the origin
+            .env("SSL_CERT_FILE", ca.cert_file())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start http-ytproxy");
+
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        for reader in [
+            Box::new(child.stderr.take().unwrap()) as Box<dyn std::io::Read + Send>,
+            Box::new(child.stdout.take().unwrap()),
+        ] {
+            let logs = logs.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                    logs.lock().unwrap().push(line);
+                }
+            });
+        }
+
+        let mut proxy = Self {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            child,
+            logs,
+            _config_dir: config_dir,
+        };
+        let listening = proxy
+            .wait_for_log(|line| line.starts_with("Proxy listening on "))
+            .unwrap_or_else(|| panic!("proxy did not start:\n{}", proxy.logs().join("\n")));
+        proxy.addr = listening["Proxy listening on ".len()..].parse().unwrap();
+        proxy
+    }
+
+    pub fn logs(&self) -> Vec<String> {
+        self.logs.lock().unwrap().clone()
+    }
+
+    // First log line matching `predicate`, waiting up to five seconds for it
+    pub fn wait_for_log(&self, predicate: impl Fn(&str) -> bool) -> Option<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(line) = self.logs().into_iter().find(|line| predicate(line)) {
+                return Some(line);
+            }
+            if Instant::now() > deadline {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    pub fn assert_logged(&self, expected: &str) {
+        if self.wait_for_log(|line| line.contains(expected)).is_none() {
+            panic!("expected log line containing {:?}, got:\n{}", expected, self.logs().join("\n"));
+        }
+    }
+}
+
+impl Drop for ProxyProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Response as seen by the player
+#[derive(Debug)]
+pub struct Fetched {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Fetched {
+    pub fn content_range(&self) -> Option<&str> {
+        self.headers.get(CONTENT_RANGE)?.to_str().ok()
+    }
+}
+
+// Player-like client: one CONNECT tunnel through the proxy, TLS that trusts
+// the test CA, then keep-alive HTTP/1.1 requests on that connection
+pub struct PlayerClient {
+    sender: SendRequest<Body>,
+    authority: String,
+}
+
+impl PlayerClient {
+    pub async fn connect(proxy: SocketAddr, origin: &Origin, ca: &TestCa) -> Self {
+        let authority = format!("{}:{}", ORIGIN_HOST, origin.addr.port());
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream
+            .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority).as_bytes())
+            .await
+            .unwrap();
+
+        // Read the CONNECT response head byte by byte so no TLS data is consumed
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let byte = stream.read_u8().await.unwrap();
+            head.push(byte);
+        }
+        let head = String::from_utf8_lossy(&head);
+        assert!(head.starts_with("HTTP/1.1 200"), "CONNECT failed: {}", head);
+
+        let root = native_tls::Certificate::from_pem(&ca.cert.to_pem().unwrap()).unwrap();
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(root)
+            .build()
+            .unwrap();
+        let tls = tokio_native_tls::TlsConnector::from(connector)
+            .connect(ORIGIN_HOST, stream)
+            .await
+            .unwrap();
+
+        let (sender, connection) = hyper::client::conn::handshake(tls).await.unwrap();
+        tokio::spawn(connection);
+        Self { sender, authority }
+    }
+
+    pub async fn get(&mut self, path: &str, range: Option<&str>) -> Fetched {
+        let mut request = Request::get(path).header("Host", &self.authority);
+        if let Some(range) = range {
+            request = request.header(RANGE, range);
+        }
+        let response = self
+            .sender
+            .send_request(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        Fetched { status, headers, body }
+    }
+}
@@ -0,0 +1,98 @@
+// Self-diagnostics
+
+mod common;
+
+use std::net::TcpListener;
+
+use common::TestCa;
+use http_ytproxy::doctor::{self, Finding, Status};
+use http_ytproxy::Config;
+
+fn config_for(ca: &TestCa) -> Config {
+    let mut config = Config::default();
+    config.proxy.cert_file = ca.cert_file().to_string_lossy().into_owned();
+    config.proxy.key_file = ca.key_file().to_string_lossy().into_owned();
+    config.proxy.port = 0;
+    config
+}
+
+fn finding<'a>(findings: &'a [Finding], check: &str) -> &'a Finding {
+    findings
+        .iter()
+        .find(|finding| finding.check == check)
+        .unwrap_or_else(|| panic!("no {} check in {:?}", check, findings))
+}
+
+#[test]
+fn working_setup_passes() {
+    let ca = TestCa::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = config_for(&ca);
+    config.tee.enabled = true;
+    config.tee.dir = dir.path().join("saved").to_string_lossy().into_owned();
+
+    let findings = doctor::check_settings(&config);
+    assert!(findings.iter().all(|finding| finding.status != Status::Failed), "{:?}", findings);
+    // The test CA is only valid for a day
+    let expiry = finding(&findings, "expiry");
+    assert_eq!(expiry.status, Status::Warning);
+    assert!(expiry.fix.as_deref().unwrap().contains("openssl req"));
+    // Checking does not create anything
+    assert!(!dir.path().join("saved").exists());
+}
+
+#[test]
+fn broken_setup_is_reported_with_fixes() {
+    let ca = TestCa::generate();
+    let dir = tempfile::tempdir().unwrap();
+
+    let bad_toml = dir.path().join("config.toml");
+    std::fs::write(&bad_toml, "[proxy]\nchunk_size = \"ten\"\n").unwrap();
+    let (_, loaded) = doctor::check_config_file(&bad_toml, true);
+    assert_eq!(loaded.status, Status::Failed);
+    assert!(loaded.message.contains("Invalid size format"), "{}", loaded.message);
+    let (_, missing) = doctor::check_config_file(&dir.path().join("missing.toml"), true);
+    assert_eq!(missing.status, Status::Failed);
+
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = config_for(&ca);
+    config.security.passphrase = Some("not the passphrase".to_string());
+    config.proxy.port = taken.local_addr().unwrap().port();
+    config.proxy.chunk_size = 0;
+    let findings = doctor::check_settings(&config);
+
+    let settings = finding(&findings, "settings");
+    assert_eq!(settings.status, Status::Failed);
+    assert!(settings.message.contains("Chunk size"));
+
+    // The key was made with the default passphrase, which the configured one hides
+    let certificate = finding(&findings, "certificate");
+    assert_eq!(certificate.status, Status::Failed);
+    assert!(certificate.message.contains("[security] passphrase"));
+    assert!(certificate.fix.as_deref().unwrap().contains("opens with the default passphrase"));
+
+    let port = finding(&findings, "port");
+    assert_eq!(port.status, Status::Failed);
+    assert!(port.message.contains("in use"));
+}
+
+#[test]
+fn website_mistakes_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+
+    std::fs::write(&path, "[websites]\nyoutub = false\n").unwrap();
+    let (_, typo) = doctor::check_config_file(&path, true);
+    assert_eq!(typo.status, Status::Failed);
+    assert!(typo.message.contains("unknown field `youtub`"), "{}", typo.message);
+
+    std::fs::write(
+        &path,
+        "[websites]\ncustom_domains = [\"video.site.com\"]\n\n[websites.profiles.\"video.site.org\"]\nchunk_size = \"2MB\"\n",
+    )
+    .unwrap();
+    let (config, loaded) = doctor::check_config_file(&path, true);
+    assert_eq!(loaded.status, Status::Ok);
+    let error = config.validate().unwrap_err();
+    assert!(error.contains("[websites.profiles.\"video.site.org\"]"), "{}", error);
+}
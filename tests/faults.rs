@@ -0,0 +1,80 @@
+// Fault injection; run with `cargo test --features fault-injection`
+#![cfg(feature = "fault-injection")]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{Origin, PlayerClient, ProxyProcess, TestCa, DATA_PATH};
+use hyper::StatusCode;
+
+const CHUNK: u64 = 64 * 1024;
+
+#[tokio::test]
+async fn injected_server_error_is_retried() {
+    let ca = TestCa::generate();
+    let origin = Origin::start(&ca, None).await;
+    let config = "[proxy]\nretries = 2\nretry_backoff_ms = 10\n\n[faults]\nenabled = true\nevery = 2\nkinds = [\"5xx\"]\n";
+    let proxy = ProxyProcess::start(&ca, CHUNK, config);
+    let mut client = PlayerClient::connect(proxy.addr, &origin, &ca).await;
+    let data = common::test_data();
+
+    // The second upstream response is broken; its retry is the third
+    for start in [0, CHUNK] {
+        let response = client.get(DATA_PATH, Some(&format!("bytes={}-", start))).await;
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.body, data[start as usize..(start + CHUNK) as usize]);
+    }
+
+    assert_eq!(origin.ranges(DATA_PATH).len(), 3);
+    proxy.assert_logged("Fault injected: 5xx");
+    proxy.assert_logged(&format!("Chunk {}-{}: 503 Service Unavailable, retry 1/2", CHUNK, 2 * CHUNK - 1));
+}
+
+#[tokio::test]
+async fn injected_429_waits_for_retry_after() {
+    let ca = TestCa::generate();
+    let origin = Origin::start(&ca, None).await;
+    let config = "[proxy]\nretries = 2\nretry_backoff_ms = 10\n\n[faults]\nenabled = true\nevery = 2\nkinds = [\"429\"]\n";
+    let proxy = ProxyProcess::start(&ca, CHUNK, config);
+    let mut client = PlayerClient::connect(proxy.addr, &origin, &ca).await;
+    let data = common::test_data();
+
+    client.get(DATA_PATH, Some("bytes=0-")).await;
+    let started = Instant::now();
+    let response = client.get(DATA_PATH, Some(&format!("bytes={}-", CHUNK))).await;
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.body, data[CHUNK as usize..(2 * CHUNK) as usize]);
+    // The injected 429 asks for one second, more than the configured backoff
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    proxy.assert_logged(&format!(
+        "Chunk {}-{}: 429 Too Many Requests, retry 1/2 in 1000ms",
+        CHUNK,
+        2 * CHUNK - 1
+    ));
+}
+
+#[tokio::test]
+async fn same_seed_gives_same_faults() {
+    let ca = TestCa::generate();
+    let origin = Origin::start(&ca, None).await;
+    // Without retries every injected 429 reaches the player
+    let config = "[proxy]\nretries = 0\n\n[faults]\nenabled = true\nseed = 7\nprobability = 0.5\nkinds = [\"429\"]\n";
+
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let proxy = ProxyProcess::start(&ca, CHUNK, config);
+        let mut client = PlayerClient::connect(proxy.addr, &origin, &ca).await;
+        let mut statuses = Vec::new();
+        for i in 0..16u64 {
+            let range = format!("bytes={}-{}", i * 100, i * 100 + 99);
+            statuses.push(client.get(DATA_PATH, Some(&range)).await.status);
+        }
+        proxy.assert_logged("Fault injection enabled: seed 7");
+        runs.push(statuses);
+    }
+
+    assert_eq!(runs[0], runs[1]);
+    assert!(runs[0].contains(&StatusCode::TOO_MANY_REQUESTS));
+    assert!(runs[0].contains(&StatusCode::PARTIAL_CONTENT));
+}
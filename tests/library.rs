@@ -0,0 +1,54 @@
+// Embedding the proxy through the library API
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Origin, PlayerClient, TestCa, DATA_PATH, ORIGIN_HOST};
+use http_ytproxy::config::HostAddresses;
+use http_ytproxy::{Config, ProxyBuilder};
+use hyper::StatusCode;
+
+#[tokio::test]
+async fn builder_serves_and_shuts_down() {
+    let ca = TestCa::generate();
+    // Upstream TLS trusts the test CA; this is the only test in this binary
+    std::env::set_var("SSL_CERT_FILE", ca.cert_file());
+    let origin = Origin::start(&ca, None).await;
+
+    let mut config = Config::default();
+    config.proxy.chunk_size = 4096;
+    config.websites.custom_domains.push(ORIGIN_HOST.to_string());
+    config
+        .dns
+        .hosts
+        .insert(ORIGIN_HOST.to_string(), HostAddresses::One([127, 0, 0, 1].into()));
+
+    let proxy = ProxyBuilder::new(config)
+        .certificate_authority(ca.authority())
+        .build()
+        .unwrap();
+    assert!(proxy
+        .describe()
+        .iter()
+        .any(|line| line.starts_with("Memory pool enabled")));
+    let bound = proxy.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = bound.local_addr();
+    assert_ne!(addr.port(), 0);
+    let shutdown = bound.shutdown_handle();
+    let server = tokio::spawn(bound.run());
+
+    let mut client = PlayerClient::connect(addr, &origin, &ca).await;
+    let response = client.get(DATA_PATH, Some("bytes=0-")).await;
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.body, common::test_data()[..4096]);
+    drop(client);
+
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("proxy did not stop after shutdown")
+        .unwrap()
+        .unwrap();
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+}
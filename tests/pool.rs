@@ -0,0 +1,134 @@
+// Byte-budgeted chunk buffer pool
+
+use std::time::Duration;
+
+use http_ytproxy::pool::BufferPool;
+use http_ytproxy::Config;
+
+const MB: usize = 1024 * 1024;
+
+#[tokio::test]
+async fn returned_buffers_are_reused() {
+    let pool = BufferPool::new(true, 64 * MB as u64);
+    let first = pool.acquire(4 * MB, Duration::ZERO).await.unwrap();
+    assert!(first.is_pooled());
+    assert_eq!(first.len(), 4 * MB);
+    drop(first);
+
+    // A slightly smaller chunk rounds up to the same size class
+    let second = pool.acquire(4 * MB - 100, Duration::ZERO).await.unwrap();
+    assert_eq!(second.len(), 4 * MB - 100);
+    let stats = pool.stats();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!(stats.reserved, 4 * MB as u64);
+    assert_eq!(stats.free, 0);
+}
+
+#[tokio::test]
+async fn exhausted_budget_waits_for_a_returned_buffer() {
+    let pool = BufferPool::new(true, 8 * MB as u64);
+    let first = pool.acquire(4 * MB, Duration::ZERO).await.unwrap();
+    let second = pool.acquire(4 * MB, Duration::ZERO).await.unwrap();
+    assert!(pool.try_acquire(4 * MB).is_none());
+
+    let waiter = tokio::spawn({
+        let pool = pool.clone();
+        async move { pool.acquire(4 * MB, Duration::from_secs(10)).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!waiter.is_finished());
+    drop(first);
+
+    let third = waiter.await.unwrap().expect("waiter was not woken");
+    assert!(third.is_pooled());
+    let stats = pool.stats();
+    assert_eq!(stats.waits, 1);
+    assert_eq!(stats.timeouts, 0);
+    assert!(stats.reserved <= stats.budget);
+    drop(second);
+}
+
+#[tokio::test]
+async fn wait_gives_up_after_the_timeout() {
+    let pool = BufferPool::new(true, 4 * MB as u64);
+    let _held = pool.acquire(4 * MB, Duration::ZERO).await.unwrap();
+    assert!(pool.acquire(MB, Duration::from_millis(50)).await.is_none());
+    assert_eq!(pool.stats().timeouts, 1);
+
+    // A buffer larger than the whole budget is refused without waiting
+    let fresh = BufferPool::new(true, 4 * MB as u64);
+    assert!(fresh.acquire(8 * MB, Duration::from_secs(10)).await.is_none());
+    assert_eq!(fresh.stats().waits, 0);
+}
+
+#[tokio::test]
+async fn free_buffers_of_other_sizes_are_evicted() {
+    let pool = BufferPool::new(true, 16 * MB as u64);
+    let small: Vec<_> = (0..4).map(|_| pool.try_acquire(MB).unwrap()).collect();
+    let held = pool.try_acquire(8 * MB).unwrap();
+    drop(small);
+    assert_eq!(pool.stats().free, 4 * MB as u64);
+
+    // Only the 4MB kept free leaves room for another 8MB buffer
+    let large = pool.try_acquire(8 * MB).expect("free buffers were not evicted");
+    let stats = pool.stats();
+    assert_eq!(stats.evictions, 4);
+    assert_eq!(stats.free, 0);
+    assert_eq!(stats.reserved, 16 * MB as u64);
+    drop((held, large));
+
+    // Returned buffers beyond half the budget are released, not kept
+    let stats = pool.stats();
+    assert_eq!(stats.free, 8 * MB as u64);
+    assert_eq!(stats.reserved, 8 * MB as u64);
+}
+
+#[tokio::test]
+async fn disabled_pool_allocates_outside_the_budget() {
+    let pool = BufferPool::new(false, MB as u64);
+    let buffers: Vec<_> = (0..4).map(|_| pool.try_acquire(4 * MB).unwrap()).collect();
+    assert!(buffers.iter().all(|buffer| !buffer.is_pooled() && buffer.len() == 4 * MB));
+    assert_eq!(pool.stats().reserved, 0);
+}
+
+#[tokio::test]
+async fn overflow_is_capped_at_a_quarter_of_the_budget() {
+    let pool = BufferPool::new(true, 8 * MB as u64);
+    let held: Vec<_> = (0..2).map(|_| pool.try_acquire(4 * MB).unwrap()).collect();
+    assert!(pool.try_acquire(MB).is_none());
+
+    let first = pool.acquire_overflow(MB).unwrap();
+    let second = pool.acquire_overflow(MB).unwrap();
+    assert!(!first.is_pooled() && first.len() == MB);
+    assert!(pool.acquire_overflow(MB).is_none());
+    let stats = pool.stats();
+    assert_eq!((stats.overflows, stats.overflow, stats.reserved), (2, 2 * MB as u64, 8 * MB as u64));
+
+    // Overflow buffers are freed, not kept for reuse
+    drop(first);
+    assert_eq!(pool.stats().overflow, MB as u64);
+    assert!(pool.acquire_overflow(MB).is_some());
+    drop((second, held));
+    assert_eq!(pool.stats().free, 4 * MB as u64);
+}
+
+#[test]
+fn budget_below_the_smallest_buffer_is_rejected() {
+    let mut config = Config::default();
+    assert!(config.background.max_cached < config.proxy.memory_pool_budget);
+    for budget in [0, 64 * 1024 - 1] {
+        config.proxy.memory_pool_budget = budget;
+        let error = config.validate().unwrap_err();
+        assert!(error.contains("Memory pool budget"), "{}", error);
+    }
+    config.proxy.memory_pool_budget = 64 * 1024;
+    let error = config.validate().unwrap_err();
+    assert!(error.contains("must fit in the memory pool budget"), "{}", error);
+    config.proxy.chunk_size = 64 * 1024;
+    config.validate().unwrap();
+
+    // Without the pool there is no budget to check
+    config.proxy.memory_pool_enabled = false;
+    config.proxy.memory_pool_budget = 0;
+    config.validate().unwrap();
+}
@@ -0,0 +1,241 @@
+// Range rewriting through the proxy binary against a local HTTPS origin
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Origin, PlayerClient, ProxyProcess, TestCa, Throttle, DATA_LEN, DATA_PATH, PAGE_PATH};
+use hyper::StatusCode;
+
+const CHUNK: u64 = 64 * 1024;
+
+async fn setup(throttle: Option<Throttle>, extra_config: &str) -> (TestCa, Origin, ProxyProcess, PlayerClient) {
+    let ca = TestCa::generate();
+    let origin = Origin::start(&ca, throttle).await;
+    let proxy = ProxyProcess::start(&ca, CHUNK, extra_config);
+    let client = PlayerClient::connect(proxy.addr, &origin, &ca).await;
+    (ca, origin, proxy, client)
+}
+
+#[tokio::test]
+async fn open_ended_range_is_cut_to_one_chunk() {
+    let (_ca, origin, proxy, mut client) = setup(None, "").await;
+    let data = common::test_data();
+
+    let response = client.get(DATA_PATH, Some("bytes=0-")).await;
+
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.content_range(),
+        Some(format!("bytes 0-{}/{}", CHUNK - 1, DATA_LEN).as_str())
+    );
+    assert_eq!(response.body, data[..CHUNK as usize]);
+    assert_eq!(origin.ranges(DATA_PATH), [Some(format!("bytes=0-{}", CHUNK - 1))]);
+    proxy.assert_logged(&format!(
+        "Range chunked: bytes=0- -> bytes=0-{} (chunk size: {})",
+        CHUNK - 1,
+        CHUNK
+    ));
+}
+
+#[tokio::test]
+async fn large_closed_range_is_cut_from_its_start() {
+    let (_ca, origin, proxy, mut client) = setup(None, "").await;
+    let data = common::test_data();
+
+    let response = client.get(DATA_PATH, Some("bytes=1000-899999")).await;
+
+    let end = 1000 + CHUNK - 1;
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.content_range(),
+        Some(format!("bytes 1000-{}/{}", end, DATA_LEN).as_str())
+    );
+    assert_eq!(response.body, data[1000..=end as usize]);
+    assert_eq!(origin.ranges(DATA_PATH), [Some(format!("bytes=1000-{}", end))]);
+    proxy.assert_logged(&format!("Range chunked: bytes=1000-899999 -> bytes=1000-{}", end));
+}
+
+#[tokio::test]
+async fn small_range_is_forwarded_unchanged() {
+    let (_ca, origin, proxy, mut client) = setup(None, "").await;
+    let data = common::test_data();
+
+    let response = client.get(DATA_PATH, Some("bytes=100-199")).await;
+
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.content_range(),
+        Some(format!("bytes 100-199/{}", DATA_LEN).as_str())
+    );
+    assert_eq!(response.body, data[100..200]);
+    assert_eq!(origin.ranges(DATA_PATH), [Some("bytes=100-199".to_string())]);
+    proxy.assert_logged("Range unchanged: bytes=100-199 (already optimal)");
+}
+
+#[tokio::test]
+async fn overlapping_closed_ranges_are_served_in_full() {
+    let (_ca, origin, proxy, mut client) = setup(None, "").await;
+    let data = common::test_data();
+
+    client.get(DATA_PATH, Some("bytes=0-999")).await;
+    // The first download only covers the start of this one
+    let response = client.get(DATA_PATH, Some("bytes=500-1499")).await;
+
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.content_range(),
+        Some(format!("bytes 500-1499/{}", DATA_LEN).as_str())
+    );
+    assert_eq!(response.body, data[500..1500]);
+    assert_eq!(
+        origin.ranges(DATA_PATH),
+        [Some("bytes=0-999".to_string()), Some("bytes=500-1499".to_string())]
+    );
+
+    // One inside the first is served from it
+    let inside = client.get(DATA_PATH, Some("bytes=600-699")).await;
+    assert_eq!(inside.body, data[600..700]);
+    assert_eq!(origin.ranges(DATA_PATH).len(), 2);
+    proxy.assert_logged("Coalesced: bytes=600-699 served from in-flight chunk 0-999");
+}
+
+#[tokio::test]
+async fn inverted_range_is_forwarded_unchanged() {
+    let (_ca, origin, proxy, mut client) = setup(None, "").await;
+
+    let response = client.get(DATA_PATH, Some("bytes=100-50")).await;
+
+    assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(origin.ranges(DATA_PATH), [Some("bytes=100-50".to_string())]);
+    proxy.assert_logged("Range unchanged: bytes=100-50 is not a single byte range");
+    // The proxy is still serving
+    let next = client.get(DATA_PATH, Some("bytes=0-")).await;
+    assert_eq!(next.status, StatusCode::PARTIAL_CONTENT);
+}
+
+#[tokio::test]
+async fn range_at_the_largest_offsets_is_not_prefetched() {
+    let config = "[proxy]\nparallel_downloads = true\n";
+    let (_ca, origin, _proxy, mut client) = setup(None, config).await;
+
+    let range = format!("bytes={}-{}", u64::MAX - 600, u64::MAX - 500);
+    let response = client.get(DATA_PATH, Some(&range)).await;
+
+    assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(origin.ranges(DATA_PATH), [Some(range)]);
+    let next = client.get(DATA_PATH, Some("bytes=0-")).await;
+    assert_eq!(next.status, StatusCode::PARTIAL_CONTENT);
+}
+
+#[tokio::test]
+async fn last_chunk_ends_at_the_learned_length() {
+    let (_ca, origin, proxy, mut client) = setup(None, "").await;
+    let data = common::test_data();
+
+    // The first response teaches the proxy the resource length
+    client.get(DATA_PATH, Some("bytes=0-")).await;
+    let start = DATA_LEN as u64 - 1000;
+    let response = client.get(DATA_PATH, Some(&format!("bytes={}-", start))).await;
+
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.content_range(),
+        Some(format!("bytes {}-{}/{}", start, DATA_LEN - 1, DATA_LEN).as_str())
+    );
+    assert_eq!(response.body, data[start as usize..]);
+    assert_eq!(
+        origin.ranges(DATA_PATH).last().cloned().flatten(),
+        Some(format!("bytes={}-{}", start, DATA_LEN - 1))
+    );
+    proxy.assert_logged(&format!("-> bytes={}-{}", start, DATA_LEN - 1));
+}
+
+#[tokio::test]
+async fn range_past_the_end_is_left_for_a_416() {
+    let (_ca, origin, proxy, mut client) = setup(None, "").await;
+
+    client.get(DATA_PATH, Some("bytes=0-")).await;
+    let range = format!("bytes={}-", DATA_LEN + 10);
+    let response = client.get(DATA_PATH, Some(&range)).await;
+
+    assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        response.content_range(),
+        Some(format!("bytes */{}", DATA_LEN).as_str())
+    );
+    assert_eq!(origin.ranges(DATA_PATH).last().cloned().flatten(), Some(range.clone()));
+    proxy.assert_logged(&format!("Range unchanged: {} starts past the resource end", range));
+}
+
+#[tokio::test]
+async fn non_media_is_passed_through() {
+    let (_ca, origin, proxy, mut client) = setup(None, "").await;
+
+    // Learn the type, then ask for a range of it
+    let page = client.get(PAGE_PATH, None).await;
+    assert_eq!(page.status, StatusCode::OK);
+    let response = client.get(PAGE_PATH, Some("bytes=0-")).await;
+
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.body, page.body);
+    assert_eq!(origin.ranges(PAGE_PATH), [None, Some("bytes=0-".to_string())]);
+    proxy.assert_logged("Range unchanged: text/html is not media");
+}
+
+#[tokio::test]
+async fn disabled_site_is_tunnelled() {
+    let config = "[websites.profiles.\"video.test\"]\nenabled = false\n";
+    let (_ca, origin, proxy, mut client) = setup(None, config).await;
+
+    let response = client.get(DATA_PATH, Some("bytes=0-")).await;
+
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.body, common::test_data());
+    assert_eq!(origin.ranges(DATA_PATH), [Some("bytes=0-".to_string())]);
+    proxy.assert_logged("Tunnelling video.test:");
+}
+
+#[tokio::test]
+async fn disabled_site_is_passed_through() {
+    // Decrypted, but forwarded as the player sent it
+    let config = "[proxy]\nselective_mitm = false\n\n[websites.profiles.\"video.test\"]\nenabled = false\n";
+    let (_ca, origin, proxy, mut client) = setup(None, config).await;
+    let data = common::test_data();
+
+    let response = client.get(DATA_PATH, Some("bytes=0-")).await;
+
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.body, data);
+    assert_eq!(origin.ranges(DATA_PATH), [Some("bytes=0-".to_string())]);
+    proxy.assert_logged("Range unchanged: video.test is not an enabled site");
+}
+
+#[tokio::test]
+async fn throttled_playback_reassembles_the_file() {
+    let throttle = Throttle {
+        bytes: 16 * 1024,
+        delay: Duration::from_millis(2),
+    };
+    let config = "[proxy]\nparallel_downloads = true\nprefetch_ahead = \"256KB\"\nmax_concurrent_chunks = 4\n";
+    let (_ca, origin, proxy, mut client) = setup(Some(throttle), config).await;
+    let data = common::test_data();
+
+    // Like mpv: ask for the rest of the file and continue where the answer stops
+    let mut received = Vec::with_capacity(DATA_LEN);
+    while received.len() < DATA_LEN {
+        let response = client.get(DATA_PATH, Some(&format!("bytes={}-", received.len()))).await;
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert!(!response.body.is_empty());
+        assert!(response.body.len() as u64 <= CHUNK);
+        received.extend_from_slice(&response.body);
+    }
+
+    assert!(received == data, "reassembled file differs from the origin's");
+    proxy.assert_logged("Parallel prefetch:");
+    // Every upstream request was a bounded chunk
+    for range in origin.ranges(DATA_PATH) {
+        let range = range.expect("upstream request without Range");
+        assert!(!range.ends_with('-'), "open-ended upstream range {}", range);
+    }
+}
@@ -0,0 +1,108 @@
+// HAR recording of proxied exchanges
+
+mod common;
+
+use std::time::Duration;
+
+use base64::Engine;
+use common::{Origin, PlayerClient, TestCa, DATA_PATH, ORIGIN_HOST};
+use http_ytproxy::config::HostAddresses;
+use http_ytproxy::har::HarRecorder;
+use http_ytproxy::{Config, ProxyBuilder};
+use hyper::StatusCode;
+use serde_json::Value;
+
+const CHUNK: usize = 4096;
+
+#[tokio::test]
+async fn exchanges_are_written_as_har() {
+    let ca = TestCa::generate();
+    // Upstream TLS trusts the test CA; this is the only test in this binary
+    std::env::set_var("SSL_CERT_FILE", ca.cert_file());
+    let origin = Origin::start(&ca, None).await;
+    let har_dir = tempfile::tempdir().unwrap();
+    let har_path = har_dir.path().join("session.har");
+
+    let mut config = Config::default();
+    config.proxy.chunk_size = CHUNK as u64;
+    config.websites.custom_domains.push(ORIGIN_HOST.to_string());
+    config
+        .dns
+        .hosts
+        .insert(ORIGIN_HOST.to_string(), HostAddresses::One([127, 0, 0, 1].into()));
+
+    let bound = ProxyBuilder::new(config)
+        .certificate_authority(ca.authority())
+        .record(HarRecorder::create(&har_path, true).unwrap())
+        .build()
+        .unwrap()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let addr = bound.local_addr();
+    let shutdown = bound.shutdown_handle();
+    let server = tokio::spawn(bound.run());
+
+    let mut client = PlayerClient::connect(addr, &origin, &ca).await;
+    let media = format!("{}?itag=22&sig=s3cr3t", DATA_PATH);
+    let response = client.get(&media, Some("bytes=0-")).await;
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(client.get("/page.html", None).await.status, StatusCode::OK);
+    drop(client);
+
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("proxy did not stop after shutdown")
+        .unwrap()
+        .unwrap();
+
+    let har: Value = serde_json::from_slice(&std::fs::read(&har_path).unwrap()).unwrap();
+    let entries = har["log"]["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 3);
+    let find = |kind: &str, filter: &dyn Fn(&Value) -> bool| {
+        entries
+            .iter()
+            .find(|entry| filter(entry))
+            .unwrap_or_else(|| panic!("no {} entry in {}", kind, har))
+    };
+
+    let chunk = find("player chunk", &|entry| entry.get("_originalRange").is_some());
+    let url = chunk["request"]["url"].as_str().unwrap();
+    assert!(url.ends_with("/data.bin?itag=22&sig=REDACTED"), "{}", url);
+    assert!(!har.to_string().contains("s3cr3t"));
+    assert_eq!(chunk["_originalRange"], "bytes=0-");
+    assert_eq!(chunk["_rewrittenRange"], format!("bytes=0-{}", CHUNK - 1));
+    assert_eq!(chunk["response"]["status"], 206);
+    assert_eq!(chunk["response"]["content"]["size"], CHUNK);
+    assert!(chunk.get("_error").is_none(), "{}", chunk["_error"]);
+    let body = base64::engine::general_purpose::STANDARD
+        .decode(chunk["response"]["content"]["text"].as_str().unwrap())
+        .unwrap();
+    assert_eq!(body, common::test_data()[..CHUNK]);
+
+    // The chunk's own upstream request, linked by its id; its bytes are in the
+    // player's entry already
+    let upstream = find("upstream", &|entry| entry.get("_attempt").is_some());
+    assert_eq!(upstream["_attempt"], 1);
+    assert_eq!(upstream["_chunk"], chunk["_chunk"]);
+    assert!(upstream["_chunk"].as_u64().is_some());
+    assert!(upstream.get("_hedge").is_none());
+    assert_eq!(upstream["response"]["status"], 206);
+    assert_eq!(upstream["response"]["content"]["size"], CHUNK);
+    assert!(upstream["response"]["content"].get("text").is_none());
+    assert!(upstream.get("_error").is_none(), "{}", upstream["_error"]);
+    let range = upstream["request"]["headers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|header| header["name"] == "range")
+        .unwrap();
+    assert_eq!(range["value"], format!("bytes=0-{}", CHUNK - 1));
+
+    let page = find("page", &|entry| entry["response"]["status"] == 200);
+    assert_eq!(page["response"]["status"], 200);
+    assert!(page.get("_originalRange").is_none());
+    assert!(page.get("_rewrittenRange").is_none());
+    assert!(page.get("_chunk").is_none());
+    assert!(page["timings"]["wait"].as_f64().unwrap() >= 0.0);
+}
@@ -0,0 +1,87 @@
+// HAR entries for retried chunk downloads; run with `cargo test --features fault-injection`
+#![cfg(feature = "fault-injection")]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Origin, PlayerClient, TestCa, DATA_PATH, ORIGIN_HOST};
+use http_ytproxy::config::{FaultKind, HostAddresses};
+use http_ytproxy::har::HarRecorder;
+use http_ytproxy::{Config, ProxyBuilder};
+use hyper::StatusCode;
+use serde_json::Value;
+
+const CHUNK: usize = 4096;
+
+#[tokio::test]
+async fn every_upstream_attempt_is_recorded() {
+    let ca = TestCa::generate();
+    // Upstream TLS trusts the test CA; this is the only test in this binary
+    std::env::set_var("SSL_CERT_FILE", ca.cert_file());
+    let origin = Origin::start(&ca, None).await;
+    let har_dir = tempfile::tempdir().unwrap();
+    let har_path = har_dir.path().join("session.har");
+
+    let mut config = Config::default();
+    config.proxy.chunk_size = CHUNK as u64;
+    config.proxy.retries = 2;
+    config.proxy.retry_backoff_ms = 10;
+    config.faults.enabled = true;
+    config.faults.every = 2;
+    config.faults.kinds = vec![FaultKind::ServerError];
+    config.websites.custom_domains.push(ORIGIN_HOST.to_string());
+    config
+        .dns
+        .hosts
+        .insert(ORIGIN_HOST.to_string(), HostAddresses::One([127, 0, 0, 1].into()));
+
+    let bound = ProxyBuilder::new(config)
+        .certificate_authority(ca.authority())
+        .record(HarRecorder::create(&har_path, false).unwrap())
+        .build()
+        .unwrap()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let addr = bound.local_addr();
+    let shutdown = bound.shutdown_handle();
+    let server = tokio::spawn(bound.run());
+
+    // The second upstream response is broken; its retry is the third
+    let mut client = PlayerClient::connect(addr, &origin, &ca).await;
+    for start in [0, CHUNK] {
+        let response = client.get(DATA_PATH, Some(&format!("bytes={}-", start))).await;
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    }
+    drop(client);
+
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("proxy did not stop after shutdown")
+        .unwrap()
+        .unwrap();
+
+    let har: Value = serde_json::from_slice(&std::fs::read(&har_path).unwrap()).unwrap();
+    let entries = har["log"]["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 5, "{}", har);
+
+    let second = format!("bytes={}-", CHUNK);
+    let player = entries
+        .iter()
+        .find(|entry| entry["_originalRange"] == second.as_str())
+        .unwrap_or_else(|| panic!("no player entry for the second chunk in {}", har));
+    assert_eq!(player["response"]["status"], 206);
+    let mut attempts: Vec<_> = entries
+        .iter()
+        .filter(|entry| entry.get("_attempt").is_some() && entry["_chunk"] == player["_chunk"])
+        .collect();
+    attempts.sort_by_key(|entry| entry["_attempt"].as_u64());
+
+    assert_eq!(attempts.len(), 2, "{}", har);
+    assert_eq!(attempts[0]["_attempt"], 1);
+    assert_eq!(attempts[0]["response"]["status"], 503);
+    assert_eq!(attempts[1]["_attempt"], 2);
+    assert_eq!(attempts[1]["response"]["status"], 206);
+    assert_eq!(attempts[1]["response"]["content"]["size"], CHUNK);
+}
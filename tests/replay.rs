@@ -0,0 +1,133 @@
+// Offline replay of a recorded session
+
+mod common;
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use common::{Origin, PlayerClient, TestCa, DATA_LEN, DATA_PATH, ORIGIN_HOST, PAGE_PATH};
+use http_ytproxy::config::{HostAddresses, SiteProfile};
+use http_ytproxy::har::HarRecorder;
+use http_ytproxy::replay::Replay;
+use http_ytproxy::stream_key::StreamKeyRules;
+use http_ytproxy::{Config, ProxyBuilder, ShutdownHandle};
+use hyper::StatusCode;
+use tokio::task::JoinHandle;
+
+type Server = JoinHandle<Result<(), hyper::Error>>;
+
+fn config(chunk_size: u64) -> Config {
+    let mut config = Config::default();
+    config.proxy.chunk_size = chunk_size;
+    config.websites.custom_domains.push(ORIGIN_HOST.to_string());
+    config
+        .dns
+        .hosts
+        .insert(ORIGIN_HOST.to_string(), HostAddresses::One([127, 0, 0, 1].into()));
+    config
+}
+
+fn start(
+    ca: &TestCa,
+    config: Config,
+    with: impl FnOnce(ProxyBuilder) -> ProxyBuilder,
+) -> (SocketAddr, ShutdownHandle, Server) {
+    let bound = with(ProxyBuilder::new(config).certificate_authority(ca.authority()))
+        .build()
+        .unwrap()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    (bound.local_addr(), bound.shutdown_handle(), tokio::spawn(bound.run()))
+}
+
+async fn stop(shutdown: ShutdownHandle, server: Server) {
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("proxy did not stop after shutdown")
+        .unwrap()
+        .unwrap();
+}
+
+async fn record(ca: &TestCa, origin: &Origin, path: &Path) {
+    let recorder = HarRecorder::create(path, true).unwrap();
+    let (addr, shutdown, server) = start(ca, config(4096), |builder| builder.record(recorder));
+    let mut client = PlayerClient::connect(addr, origin, ca).await;
+    for range in ["bytes=0-", "bytes=4096-"] {
+        assert_eq!(client.get(DATA_PATH, Some(range)).await.status, StatusCode::PARTIAL_CONTENT);
+    }
+    assert_eq!(client.get(PAGE_PATH, None).await.status, StatusCode::OK);
+    drop(client);
+    stop(shutdown, server).await;
+}
+
+#[tokio::test]
+async fn recorded_session_replays_without_upstream() {
+    let ca = TestCa::generate();
+    // Upstream TLS trusts the test CA; this is the only test in this binary
+    std::env::set_var("SSL_CERT_FILE", ca.cert_file());
+    let origin = Origin::start(&ca, None).await;
+    let har_dir = tempfile::tempdir().unwrap();
+    let har_path = har_dir.path().join("session.har");
+    record(&ca, &origin, &har_path).await;
+    let upstream_requests = origin.ranges(DATA_PATH).len() + origin.ranges(PAGE_PATH).len();
+
+    // A different chunk size is served from the recorded bytes
+    let replay = Replay::load(&har_path, StreamKeyRules::default(), false).unwrap();
+    let (addr, shutdown, server) = start(&ca, config(2048), |builder| builder.replay(replay));
+    let mut client = PlayerClient::connect(addr, &origin, &ca).await;
+    let data = common::test_data();
+
+    let first = client.get(DATA_PATH, Some("bytes=0-")).await;
+    assert_eq!(first.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(first.content_range(), Some(format!("bytes 0-2047/{}", DATA_LEN).as_str()));
+    assert_eq!(first.body, data[..2048]);
+
+    // Spans the two recorded chunks
+    let spanning = client.get(DATA_PATH, Some("bytes=3000-5000")).await;
+    assert_eq!(spanning.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(spanning.body, data[3000..=5000]);
+
+    let page = client.get(PAGE_PATH, None).await;
+    assert_eq!(page.status, StatusCode::OK);
+
+    let unrecorded = client.get(DATA_PATH, Some("bytes=500000-")).await;
+    assert_eq!(unrecorded.status, StatusCode::NOT_FOUND);
+
+    let past_end = client.get(DATA_PATH, Some(&format!("bytes={}-", DATA_LEN + 10))).await;
+    assert_eq!(past_end.status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+    drop(client);
+    stop(shutdown, server).await;
+
+    // Without a total length, an open range passed through unchanged gets what
+    // was recorded from its start
+    let har = std::fs::read_to_string(&har_path).unwrap();
+    let unknown_length = har.replace(&format!("/{}\"", DATA_LEN), "/*\"");
+    assert_ne!(har, unknown_length);
+    let unknown_path = har_dir.path().join("unknown-length.har");
+    std::fs::write(&unknown_path, unknown_length).unwrap();
+    let replay = Replay::load(&unknown_path, StreamKeyRules::default(), false).unwrap();
+    let mut passthrough = config(2048);
+    // Still decrypted, so the recording answers it
+    passthrough.proxy.selective_mitm = false;
+    passthrough.websites.profiles.insert(
+        ORIGIN_HOST.to_string(),
+        SiteProfile {
+            enabled: Some(false),
+            ..SiteProfile::default()
+        },
+    );
+    let (addr, shutdown, server) = start(&ca, passthrough, |builder| builder.replay(replay));
+    let mut client = PlayerClient::connect(addr, &origin, &ca).await;
+
+    let open = client.get(DATA_PATH, Some("bytes=1000-")).await;
+    assert_eq!(open.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(open.content_range(), Some("bytes 1000-8191/*"));
+    assert_eq!(open.body, data[1000..8192]);
+
+    drop(client);
+    stop(shutdown, server).await;
+    assert_eq!(origin.ranges(DATA_PATH).len() + origin.ranges(PAGE_PATH).len(), upstream_requests);
+}
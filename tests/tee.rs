@@ -0,0 +1,66 @@
+// Saving played streams to disk
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use common::{Origin, PlayerClient, ProxyProcess, TestCa, DATA_LEN, DATA_PATH, PAGE_PATH};
+use hyper::StatusCode;
+
+const CHUNK: u64 = 256 * 1024;
+
+// Poll `dir` until a file matching `found` shows up
+fn wait_for_file(dir: &Path, found: impl Fn(&Path) -> bool) -> PathBuf {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        if let Some(path) = paths.into_iter().find(|path| found(path)) {
+            return path;
+        }
+        assert!(Instant::now() < deadline, "no matching file in {}", dir.display());
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[tokio::test]
+async fn played_stream_is_saved_once_complete() {
+    let ca = TestCa::generate();
+    let origin = Origin::start(&ca, None).await;
+    let saved = tempfile::tempdir().unwrap();
+    let config = format!("[tee]\nenabled = true\ndir = {:?}\n", saved.path());
+    let proxy = ProxyProcess::start(&ca, CHUNK, &config);
+    let mut client = PlayerClient::connect(proxy.addr, &origin, &ca).await;
+    let data = common::test_data();
+
+    // One chunk in: a sparse file of full length and a sidecar listing the chunk
+    let first = client.get(DATA_PATH, Some("bytes=0-")).await;
+    assert_eq!(first.status, StatusCode::PARTIAL_CONTENT);
+    let sidecar = wait_for_file(saved.path(), |path| {
+        path.to_string_lossy().ends_with(".part.ranges")
+            && std::fs::read_to_string(path).is_ok_and(|json| json.contains(&format!("[[0,{}]]", CHUNK)))
+    });
+    let part = PathBuf::from(sidecar.to_string_lossy().trim_end_matches(".ranges"));
+    assert_eq!(std::fs::metadata(&part).unwrap().len(), DATA_LEN as u64);
+
+    // Pages are not saved
+    assert_eq!(client.get(PAGE_PATH, None).await.status, StatusCode::OK);
+
+    let mut start = CHUNK;
+    while start < DATA_LEN as u64 {
+        let response = client.get(DATA_PATH, Some(&format!("bytes={}-", start))).await;
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        start += response.body.len() as u64;
+    }
+
+    let finished = wait_for_file(saved.path(), |path| path.extension().is_some_and(|ext| ext == "bin"));
+    assert!(finished.file_name().unwrap().to_string_lossy().starts_with("data-"));
+    assert_eq!(std::fs::read(&finished).unwrap(), data);
+    assert!(!part.exists());
+    assert!(!sidecar.exists());
+    assert_eq!(std::fs::read_dir(saved.path()).unwrap().count(), 1);
+    proxy.assert_logged("Stream saved: ");
+}